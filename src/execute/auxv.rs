@@ -0,0 +1,121 @@
+use alloc::vec::Vec;
+use anyhow::anyhow;
+use x86_64::instructions::random::RdRand;
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+/// Writes the initial process stack the System V ABI expects at the top of
+/// `stack`, which is the memory right below `stack_top`. Every entry is
+/// `word_size` bytes wide, 8 for 64-bit and 4 for 32-bit programs:
+///
+/// ```text
+/// argument strings
+/// 16 random bytes for AT_RANDOM, which glibc seeds its stack protector with
+/// auxv pairs, then AT_RANDOM, terminated by AT_NULL
+/// envp (empty), NULL
+/// argv pointers, NULL
+/// argc                       <- returned stack pointer, 16 byte aligned
+/// ```
+pub fn build_initial_stack(
+    stack: &mut [u8],
+    stack_top: u64,
+    args: &[&str],
+    auxv: &[(u64, u64)],
+    word_size: usize,
+) -> anyhow::Result<u64> {
+    let stack_bottom = stack_top - stack.len() as u64;
+    let mut sp = stack_top;
+
+    let mut argv = Vec::with_capacity(args.len());
+    for arg in args {
+        sp = sp
+            .checked_sub(arg.len() as u64 + 1)
+            .filter(|&sp| sp >= stack_bottom)
+            .ok_or(anyhow!("Initial stack overflow"))?;
+        let start = (sp - stack_bottom) as usize;
+        stack[start..start + arg.len()].copy_from_slice(arg.as_bytes());
+        stack[start + arg.len()] = 0;
+        argv.push(sp);
+    }
+    sp = sp
+        .checked_sub(16)
+        .filter(|&sp| sp >= stack_bottom)
+        .ok_or(anyhow!("Initial stack overflow"))?;
+    let start = (sp - stack_bottom) as usize;
+    stack[start..start + 16].copy_from_slice(&random_bytes());
+    let random = sp;
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(args.len() as u64);
+    words.extend_from_slice(&argv);
+    words.push(0);
+    words.push(0);
+    for &(key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+    words.push(AT_RANDOM);
+    words.push(random);
+    words.push(AT_NULL);
+    words.push(0);
+
+    sp = (sp & !0xf)
+        .checked_sub((words.len() * word_size) as u64)
+        .map(|sp| sp & !0xf)
+        .filter(|&sp| sp >= stack_bottom)
+        .ok_or(anyhow!("Initial stack overflow"))?;
+    let start = (sp - stack_bottom) as usize;
+    for (i, word) in words.iter().enumerate() {
+        let offset = start + i * word_size;
+        stack[offset..offset + word_size]
+            .copy_from_slice(&word.to_le_bytes()[..word_size]);
+    }
+    Ok(sp)
+}
+
+/// 16 bytes from `rdrand`, or from the time stamp counter without it.
+fn random_bytes() -> [u8; 16] {
+    let rdrand = RdRand::new();
+    let mut bytes = [0u8; 16];
+    for chunk in bytes.chunks_exact_mut(8) {
+        let word = rdrand
+            .and_then(RdRand::get_u64)
+            .unwrap_or_else(|| unsafe { core::arch::x86_64::_rdtsc() });
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+#[test_case]
+fn initial_stack_layout() {
+    let mut stack = alloc::vec![0u8; 256];
+    let top = 0x8000_0000;
+    let sp =
+        build_initial_stack(&mut stack, top, &["/a"], &[(AT_PAGESZ, 4096)], 8)
+            .unwrap();
+    assert_eq!(sp % 16, 0);
+
+    let bottom = top - stack.len() as u64;
+    let word = |addr: u64| {
+        let offset = (addr - bottom) as usize;
+        u64::from_le_bytes(stack[offset..offset + 8].try_into().unwrap())
+    };
+    assert_eq!(word(sp), 1);
+    let arg = word(sp + 8);
+    assert_eq!(&stack[(arg - bottom) as usize..][..3], b"/a\0");
+    assert_eq!(word(sp + 16), 0);
+    assert_eq!(word(sp + 24), 0);
+    assert_eq!(word(sp + 32), AT_PAGESZ);
+    assert_eq!(word(sp + 40), 4096);
+    assert_eq!(word(sp + 48), AT_RANDOM);
+    let random = word(sp + 56);
+    assert!(random >= arg - 16 && random + 16 <= top);
+    assert_eq!(word(sp + 64), AT_NULL);
+}
@@ -0,0 +1,471 @@
+use alloc::{collections::BTreeMap, format, string::String, vec, vec::Vec};
+use x86_64::{
+    registers::model_specific::FsBase,
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{PageTableFlags, Translate},
+    },
+    VirtAddr,
+};
+
+use crate::{
+    file_system::{kernel_fs, File},
+    gdt::GDT,
+    memory::with_mapper,
+    serial_println,
+};
+
+use super::{
+    elf64::{
+        ELF64SegmentFlags, ELF_CLASS_64, ELF_DATA_LITTLE_ENDIAN,
+        ELF_MACHINE_X86_64, ELF_MAGIC, PT_LOAD, PT_NOTE,
+    },
+    process::{Backing, MemoryRegion, Process, CURRENT_PROCESS},
+    swap::read_swapped,
+};
+
+pub const ELF_TYPE_CORE: u16 = 4;
+pub const NT_PRSTATUS: u32 = 1;
+
+pub const SIGILL: u32 = 4;
+pub const SIGSEGV: u32 = 11;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+const NOTE_HEADER_SIZE: usize = 12;
+
+/// Size of the x86_64 `struct elf_prstatus` gdb expects in `NT_PRSTATUS`.
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 32;
+/// Offset of `pr_reg`, a `struct user_regs_struct` of 27 registers.
+const PRSTATUS_REGS: usize = 112;
+
+// Indices into `user_regs_struct`.
+const REG_RBP: usize = 4;
+const REG_RIP: usize = 16;
+const REG_CS: usize = 17;
+const REG_RFLAGS: usize = 18;
+const REG_RSP: usize = 19;
+const REG_SS: usize = 20;
+const REG_FS_BASE: usize = 21;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The registers of a crashed process. Only what the CPU pushed on the
+/// exception, the frame pointer and the FS base are known; the rest read
+/// as zero.
+#[derive(Debug, Clone, Copy)]
+pub struct FaultRegisters {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+}
+
+impl FaultRegisters {
+    pub fn new(stack_frame: &InterruptStackFrame, rbp: u64) -> Self {
+        Self {
+            rip: stack_frame.instruction_pointer.as_u64(),
+            rsp: stack_frame.stack_pointer.as_u64(),
+            rbp,
+            rflags: stack_frame.cpu_flags.bits(),
+            cs: stack_frame.code_segment.0 as u64,
+            ss: stack_frame.stack_segment.0 as u64,
+            fs_base: FsBase::read().as_u64(),
+        }
+    }
+}
+
+/// An ELF core file of a process: a `PT_NOTE` with its registers followed
+/// by one `PT_LOAD` per memory region. The memory is read from the live
+/// mappings while the file is written, or from where it would be paged in
+/// from for pages that aren't present.
+pub struct CoreImage {
+    headers: Vec<u8>,
+    /// File offset of each region's contents.
+    regions: Vec<(usize, MemoryRegion)>,
+    /// Swap slots of the pages of the process that were evicted.
+    swapped: BTreeMap<u64, usize>,
+    size: usize,
+}
+
+impl CoreImage {
+    pub fn new(
+        process: &Process,
+        signal: u32,
+        registers: &FaultRegisters,
+    ) -> Self {
+        // Guard pages are never mapped, so there is nothing to dump.
+        let dumped: Vec<&MemoryRegion> = process
+            .regions
+            .iter()
+            .filter(|r| !matches!(r.backing, Backing::Guard))
+            .collect();
+        let phnum = dumped.len() + 1;
+        let note_offset = ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE;
+        let note = prstatus_note(process.pid, signal, registers);
+
+        let mut offset = align_up(note_offset + note.len(), PAGE_SIZE as usize);
+        let mut regions = Vec::with_capacity(dumped.len());
+        for region in dumped {
+            regions.push((offset, region.clone()));
+            offset += region.size() as usize;
+        }
+
+        let mut headers = Vec::with_capacity(note_offset + note.len());
+        push_elf_header(&mut headers, phnum as u16);
+        push_program_header(
+            &mut headers,
+            PT_NOTE,
+            0,
+            note_offset as u64,
+            0,
+            note.len() as u64,
+            0,
+            4,
+        );
+        for (file_offset, region) in &regions {
+            push_program_header(
+                &mut headers,
+                PT_LOAD,
+                segment_flags(region.flags).bits(),
+                *file_offset as u64,
+                region.start,
+                region.size(),
+                region.size(),
+                PAGE_SIZE,
+            );
+        }
+        headers.extend_from_slice(&note);
+
+        Self {
+            headers,
+            regions,
+            swapped: process.swapped.clone(),
+            size: offset,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Fills `buffer` with the core file contents at `offset`. Pages that
+    /// aren't mapped are read from swap or their file, and anonymous ones
+    /// that were never touched as zero.
+    pub fn read_at(&self, offset: usize, buffer: &mut [u8]) {
+        buffer.fill(0);
+        copy_overlap(&self.headers, 0, offset, buffer);
+        let end = offset + buffer.len();
+        for (file_offset, region) in &self.regions {
+            let region_end = file_offset + region.size() as usize;
+            if end <= *file_offset || region_end <= offset {
+                continue;
+            }
+            for page in (region.start..region.end).step_by(PAGE_SIZE as usize) {
+                let page_offset = file_offset + (page - region.start) as usize;
+                if end <= page_offset
+                    || page_offset + PAGE_SIZE as usize <= offset
+                {
+                    continue;
+                }
+                if is_mapped(page) {
+                    let memory = unsafe {
+                        core::slice::from_raw_parts(
+                            page as *const u8,
+                            PAGE_SIZE as usize,
+                        )
+                    };
+                    copy_overlap(memory, page_offset, offset, buffer);
+                } else if let Some(contents) = self.unmapped_page(region, page)
+                {
+                    copy_overlap(&contents, page_offset, offset, buffer);
+                }
+            }
+        }
+    }
+
+    /// The contents of `page` of `region` while it isn't mapped: what its
+    /// swap slot or backing file holds. `None` if it is all zeros or can't
+    /// be read.
+    fn unmapped_page(
+        &self,
+        region: &MemoryRegion,
+        page: u64,
+    ) -> Option<Vec<u8>> {
+        let mut contents = vec![0u8; PAGE_SIZE as usize];
+        let read = if let Some(&slot) = self.swapped.get(&page) {
+            read_swapped(slot, &mut contents)
+        } else if let (
+            Backing::File { fs, file, .. },
+            Some((offset, at, len)),
+        ) = (&region.backing, region.file_bytes(page))
+        {
+            fs.read_bytes(file, &mut contents[at..at + len], offset as usize)
+        } else {
+            return None;
+        };
+        match read {
+            Ok(()) => Some(contents),
+            Err(e) => {
+                serial_println!(
+                    "Page {:#x} missing from the core: {}",
+                    page,
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Writes `/CORE<pid>` for the current process, which died of `signal`.
+/// The core is always an x86_64 one, so 32-bit programs in compatibility
+/// mode get none.
+pub fn write_core_dump(
+    signal: u32,
+    registers: &FaultRegisters,
+) -> anyhow::Result<File> {
+    if registers.cs == GDT.1.user_code32_selector.0 as u64 {
+        return Err(anyhow::anyhow!("No core dumps of 32-bit programs"));
+    }
+    let fs =
+        kernel_fs().ok_or(anyhow::anyhow!("No file system for core dumps"))?;
+    let process = CURRENT_PROCESS.lock();
+    let process = process
+        .as_ref()
+        .ok_or(anyhow::anyhow!("No process is running"))?;
+
+    let image = CoreImage::new(process, signal, registers);
+    fs.create_file(
+        &core_file_name(process.pid),
+        image.size(),
+        &mut |offset, buffer| image.read_at(offset, buffer),
+    )
+}
+
+/// Whether `page` is present; pages never touched or swapped out are not.
+fn is_mapped(page: u64) -> bool {
+    with_mapper(|mapper| mapper.translate_addr(VirtAddr::new(page)).is_some())
+}
+
+/// `CORE` and the last four digits of `pid`, which always fits 8.3. Only
+/// the newest of pids 10000 apart is kept.
+pub fn core_file_name(pid: u64) -> String {
+    format!("CORE{:04}", pid % 10_000)
+}
+
+/// Copies the part of `source`, placed at `source_offset` in the file, that
+/// overlaps `buffer`, placed at `offset`.
+fn copy_overlap(
+    source: &[u8],
+    source_offset: usize,
+    offset: usize,
+    buffer: &mut [u8],
+) {
+    let start = offset.max(source_offset);
+    let end = (offset + buffer.len()).min(source_offset + source.len());
+    if start < end {
+        buffer[start - offset..end - offset].copy_from_slice(
+            &source[start - source_offset..end - source_offset],
+        );
+    }
+}
+
+fn segment_flags(flags: PageTableFlags) -> ELF64SegmentFlags {
+    let mut segment_flags = ELF64SegmentFlags::READABLE;
+    if flags.contains(PageTableFlags::WRITABLE) {
+        segment_flags |= ELF64SegmentFlags::WRITABLE;
+    }
+    if !flags.contains(PageTableFlags::NO_EXECUTE) {
+        segment_flags |= ELF64SegmentFlags::EXACUTABLE;
+    }
+    segment_flags
+}
+
+fn prstatus_note(pid: u64, signal: u32, registers: &FaultRegisters) -> Vec<u8> {
+    let mut prstatus = [0u8; PRSTATUS_SIZE];
+    prstatus[0..4].copy_from_slice(&signal.to_le_bytes());
+    prstatus[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2]
+        .copy_from_slice(&(signal as u16).to_le_bytes());
+    prstatus[PRSTATUS_PID..PRSTATUS_PID + 4]
+        .copy_from_slice(&(pid as u32).to_le_bytes());
+    let registers = [
+        (REG_RBP, registers.rbp),
+        (REG_RIP, registers.rip),
+        (REG_CS, registers.cs),
+        (REG_RFLAGS, registers.rflags),
+        (REG_RSP, registers.rsp),
+        (REG_SS, registers.ss),
+        (REG_FS_BASE, registers.fs_base),
+    ];
+    for (index, value) in registers {
+        let offset = PRSTATUS_REGS + index * 8;
+        prstatus[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let mut note =
+        Vec::with_capacity(NOTE_HEADER_SIZE + NOTE_NAME.len() + PRSTATUS_SIZE);
+    note.extend_from_slice(&5u32.to_le_bytes()); // "CORE\0"
+    note.extend_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes());
+    note.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    note.extend_from_slice(NOTE_NAME);
+    note.extend_from_slice(&prstatus);
+    note
+}
+
+fn push_elf_header(out: &mut Vec<u8>, program_header_entries: u16) {
+    out.extend_from_slice(&ELF_MAGIC);
+    out.push(ELF_CLASS_64);
+    out.push(ELF_DATA_LITTLE_ENDIAN);
+    out.push(1); // version
+    out.extend_from_slice(&[0; 9]);
+    out.extend_from_slice(&ELF_TYPE_CORE.to_le_bytes());
+    out.extend_from_slice(&ELF_MACHINE_X86_64.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes()); // entry
+    out.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes()); // section headers
+    out.extend_from_slice(&0u32.to_le_bytes()); // flags
+    out.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&program_header_entries.to_le_bytes());
+    out.extend_from_slice(&[0; 6]); // no section headers
+}
+
+#[allow(clippy::too_many_arguments)]
+fn push_program_header(
+    out: &mut Vec<u8>,
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    virt_addr: u64,
+    file_size: u64,
+    memory_size: u64,
+    alignment: u64,
+) {
+    out.extend_from_slice(&segment_type.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&virt_addr.to_le_bytes());
+    out.extend_from_slice(&virt_addr.to_le_bytes());
+    out.extend_from_slice(&file_size.to_le_bytes());
+    out.extend_from_slice(&memory_size.to_le_bytes());
+    out.extend_from_slice(&alignment.to_le_bytes());
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+#[test_case]
+fn core_image_layout() {
+    let mut process = Process::new("/test.elf");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    process.add_region(0x40_0000, 0x40_2000, flags);
+    let registers = FaultRegisters {
+        rip: 0x40_1234,
+        rsp: 0x7fff_f000,
+        rbp: 0,
+        rflags: 0x202,
+        cs: 0x2b,
+        ss: 0x23,
+        fs_base: 0,
+    };
+    let image = CoreImage::new(&process, SIGSEGV, &registers);
+    assert_eq!(image.size(), 0x1000 + 0x2000);
+
+    let mut header = [0u8; ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE];
+    image.read_at(0, &mut header);
+    assert_eq!(&header[0..4], &ELF_MAGIC);
+    assert_eq!(u16::from_le_bytes([header[16], header[17]]), ELF_TYPE_CORE);
+    assert_eq!(u16::from_le_bytes([header[56], header[57]]), 2);
+    let load = &header[ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE..];
+    assert_eq!(u32::from_le_bytes(load[0..4].try_into().unwrap()), PT_LOAD);
+    assert_eq!(u64::from_le_bytes(load[8..16].try_into().unwrap()), 0x1000);
+}
+
+#[test_case]
+fn core_file_names_fit_short_names() {
+    use crate::file_system::fat16::Format83;
+
+    assert_eq!(core_file_name(7), "CORE0007");
+    assert_eq!(core_file_name(123_456), "CORE3456");
+    assert!(Format83::parse(&core_file_name(u64::MAX)).is_some());
+}
+
+/// Files whose every byte is the low byte of its offset.
+#[cfg(test)]
+struct OffsetBytes;
+
+#[cfg(test)]
+impl crate::file_system::FileSource for OffsetBytes {
+    fn read_bytes(
+        &self,
+        _file: &File,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> anyhow::Result<()> {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = (offset + i) as u8;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn unmapped_file_pages_are_read_from_the_file() {
+    use crate::file_system::TimeStamp;
+    use alloc::sync::Arc;
+
+    let file = Arc::new(File {
+        name: "TEST".into(),
+        ext: "ELF".into(),
+        path: "/TEST.ELF".into(),
+        start_sector: 0,
+        start_cluster: 0,
+        size: 0x2000,
+        time_stamp: TimeStamp::default(),
+    });
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let mut process = Process::new("/test.elf");
+    process.add_vma(MemoryRegion::new(
+        0x40_0000,
+        0x40_2000,
+        flags,
+        Backing::File {
+            fs: &OffsetBytes,
+            file,
+            offset: 0x1010,
+            start: 0x40_0800,
+            size: 0x1000,
+        },
+    ));
+    process.add_region(0x50_0000, 0x50_1000, flags);
+    let registers = FaultRegisters {
+        rip: 0x40_1234,
+        rsp: 0x7fff_f000,
+        rbp: 0,
+        rflags: 0x202,
+        cs: 0x2b,
+        ss: 0x23,
+        fs_base: 0,
+    };
+    let image = CoreImage::new(&process, SIGSEGV, &registers);
+
+    let file_region = &image.regions[0].1;
+    let page = image.unmapped_page(file_region, 0x40_0000).unwrap();
+    assert!(page[..0x800].iter().all(|&byte| byte == 0));
+    assert_eq!(&page[0x800..0x803], &[0x10, 0x11, 0x12]);
+    let page = image.unmapped_page(file_region, 0x40_1000).unwrap();
+    assert_eq!(page[0x7ff], 0x0f);
+    assert!(page[0x800..].iter().all(|&byte| byte == 0));
+    assert!(image
+        .unmapped_page(&image.regions[1].1, 0x50_0000)
+        .is_none());
+}
@@ -0,0 +1,308 @@
+use alloc::vec::Vec;
+
+use super::{
+    elf64::{ELF64ProgramHeader, ElfError},
+    symbols::ELF64Symbol,
+};
+
+pub const DT_NULL: i64 = 0;
+pub const DT_PLTRELSZ: i64 = 2;
+pub const DT_SYMTAB: i64 = 6;
+pub const DT_RELA: i64 = 7;
+pub const DT_RELASZ: i64 = 8;
+pub const DT_RELAENT: i64 = 9;
+pub const DT_SYMENT: i64 = 11;
+pub const DT_PLTREL: i64 = 20;
+pub const DT_JMPREL: i64 = 23;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_GLOB_DAT: u32 = 6;
+pub const R_X86_64_JUMP_SLOT: u32 = 7;
+pub const R_X86_64_RELATIVE: u32 = 8;
+pub const R_X86_64_IRELATIVE: u32 = 37;
+
+/// Entry of the `PT_DYNAMIC` segment.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ELF64Dynamic {
+    pub tag: i64,
+    pub value: u64,
+}
+
+/// Relocation with an explicit addend, as found in `.rela.dyn`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ELF64Rela {
+    pub offset: u64,
+    pub info: u64,
+    pub addend: i64,
+}
+
+impl ELF64Rela {
+    pub fn kind(&self) -> u32 {
+        (self.info & 0xffff_ffff) as u32
+    }
+
+    pub fn symbol(&self) -> u32 {
+        (self.info >> 32) as u32
+    }
+
+    /// The address to patch and the value to store there, or `None` for
+    /// `R_X86_64_NONE` and `R_X86_64_IRELATIVE`. The resolver of an
+    /// `IRELATIVE` is user code, so it is left to the image itself: only
+    /// glibc emits them, and both ld.so and static-pie binaries apply them
+    /// at startup. `symbol` gives the address of a symbol by its index.
+    pub fn resolve(
+        &self,
+        load_bias: u64,
+        symbol: impl Fn(u32) -> Result<u64, ElfError>,
+    ) -> Result<Option<(u64, u64)>, ElfError> {
+        let value = match self.kind() {
+            R_X86_64_NONE | R_X86_64_IRELATIVE => return Ok(None),
+            R_X86_64_RELATIVE => load_bias.wrapping_add_signed(self.addend),
+            R_X86_64_64 => {
+                symbol(self.symbol())?.wrapping_add_signed(self.addend)
+            }
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => symbol(self.symbol())?,
+            kind => return Err(ElfError::UnsupportedRelocation(kind)),
+        };
+        Ok(Some((load_bias.wrapping_add(self.offset), value)))
+    }
+}
+
+/// Location of the `.rela.dyn` table, relative to the unbiased image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelaTable {
+    pub addr: u64,
+    pub size: u64,
+    pub entry_size: u64,
+}
+
+/// Finds the `DT_JMPREL` table of PLT relocations, if any.
+pub fn find_plt_table(
+    entries: &[ELF64Dynamic],
+) -> Result<Option<RelaTable>, ElfError> {
+    let mut addr = None;
+    let mut size = 0;
+    let mut kind = DT_RELA as u64;
+    for entry in entries {
+        match entry.tag {
+            DT_NULL => break,
+            DT_JMPREL => addr = Some(entry.value),
+            DT_PLTRELSZ => size = entry.value,
+            DT_PLTREL => kind = entry.value,
+            _ => {}
+        }
+    }
+    let Some(addr) = addr else {
+        return Ok(None);
+    };
+    if kind != DT_RELA as u64 {
+        return Err(ElfError::InvalidDynamicSection(addr));
+    }
+    Ok(Some(RelaTable {
+        addr,
+        size,
+        entry_size: core::mem::size_of::<ELF64Rela>() as u64,
+    }))
+}
+
+/// Address and entry size of the `DT_SYMTAB` table, if any.
+pub fn find_symbol_table(entries: &[ELF64Dynamic]) -> Option<(u64, u64)> {
+    let mut addr = None;
+    let mut entry_size = core::mem::size_of::<ELF64Symbol>() as u64;
+    for entry in entries {
+        match entry.tag {
+            DT_NULL => break,
+            DT_SYMTAB => addr = Some(entry.value),
+            DT_SYMENT => entry_size = entry.value,
+            _ => {}
+        }
+    }
+    addr.map(|addr| (addr, entry_size))
+}
+
+/// Finds the `DT_RELA` table described by the dynamic entries, if any.
+pub fn find_rela_table(
+    entries: &[ELF64Dynamic],
+) -> Result<Option<RelaTable>, ElfError> {
+    let mut addr = None;
+    let mut size = 0;
+    let mut entry_size = core::mem::size_of::<ELF64Rela>() as u64;
+    for entry in entries {
+        match entry.tag {
+            DT_NULL => break,
+            DT_RELA => addr = Some(entry.value),
+            DT_RELASZ => size = entry.value,
+            DT_RELAENT => entry_size = entry.value,
+            _ => {}
+        }
+    }
+    let Some(addr) = addr else {
+        return Ok(None);
+    };
+    if entry_size < core::mem::size_of::<ELF64Rela>() as u64 {
+        return Err(ElfError::InvalidDynamicSection(addr));
+    }
+    Ok(Some(RelaTable {
+        addr,
+        size,
+        entry_size,
+    }))
+}
+
+/// Applies the relocations of a loaded position independent executable
+/// that relocates itself, resolving symbols against its own `DT_SYMTAB`.
+/// `dynamic` is the unbiased `PT_DYNAMIC` header, `segments` the loaded
+/// (biased) `PT_LOAD` segments, which must still be mapped writable.
+pub fn relocate(
+    dynamic: &ELF64ProgramHeader,
+    load_bias: u64,
+    segments: &[ELF64ProgramHeader],
+) -> Result<(), ElfError> {
+    let dynamic_addr = dynamic.virt_addr().wrapping_add(load_bias);
+    let dynamic_size = dynamic.memory_size();
+    if !is_loaded(segments, dynamic_addr, dynamic_size) {
+        return Err(ElfError::InvalidDynamicSection(dynamic.virt_addr()));
+    }
+    let entry_size = core::mem::size_of::<ELF64Dynamic>() as u64;
+    let entries: Vec<ELF64Dynamic> = (0..dynamic_size / entry_size)
+        .map(|index| unsafe {
+            core::ptr::read_unaligned(
+                (dynamic_addr + index * entry_size) as *const ELF64Dynamic,
+            )
+        })
+        .collect();
+
+    let symbols = find_symbol_table(&entries);
+    let symbol = |index: u32| -> Result<u64, ElfError> {
+        let (table, entry_size) = symbols
+            .ok_or(ElfError::InvalidDynamicSection(dynamic.virt_addr()))?;
+        let symbol_size = core::mem::size_of::<ELF64Symbol>() as u64;
+        let addr = table
+            .wrapping_add(load_bias)
+            .wrapping_add(index as u64 * entry_size);
+        if entry_size < symbol_size || !is_loaded(segments, addr, symbol_size) {
+            return Err(ElfError::InvalidSymbol(index));
+        }
+        let symbol =
+            unsafe { core::ptr::read_unaligned(addr as *const ELF64Symbol) };
+        symbol
+            .address(load_bias)
+            .ok_or(ElfError::InvalidSymbol(index))
+    };
+
+    let tables = [find_rela_table(&entries)?, find_plt_table(&entries)?];
+    for table in tables.into_iter().flatten() {
+        let table_addr = table.addr.wrapping_add(load_bias);
+        if !is_loaded(segments, table_addr, table.size) {
+            return Err(ElfError::InvalidDynamicSection(table.addr));
+        }
+        for index in 0..table.size / table.entry_size {
+            let rela = unsafe {
+                core::ptr::read_unaligned(
+                    (table_addr + index * table.entry_size) as *const ELF64Rela,
+                )
+            };
+            if let Some((addr, value)) = rela.resolve(load_bias, symbol)? {
+                if !is_loaded(segments, addr, 8) {
+                    return Err(ElfError::RelocationOutOfBounds(rela.offset));
+                }
+                unsafe { core::ptr::write_unaligned(addr as *mut u64, value) };
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_loaded(segments: &[ELF64ProgramHeader], addr: u64, size: u64) -> bool {
+    let Some(end) = addr.checked_add(size) else {
+        return false;
+    };
+    segments.iter().any(|segment| {
+        addr >= segment.virt_addr()
+            && end <= segment.virt_addr() + segment.memory_size()
+    })
+}
+
+#[test_case]
+fn find_rela_table_reads_dynamic_entries() {
+    let entries = [
+        ELF64Dynamic {
+            tag: DT_RELA,
+            value: 0x3e0,
+        },
+        ELF64Dynamic {
+            tag: DT_RELASZ,
+            value: 0x48,
+        },
+        ELF64Dynamic {
+            tag: DT_RELAENT,
+            value: 0x18,
+        },
+        ELF64Dynamic {
+            tag: DT_NULL,
+            value: 0,
+        },
+    ];
+    assert_eq!(
+        find_rela_table(&entries),
+        Ok(Some(RelaTable {
+            addr: 0x3e0,
+            size: 0x48,
+            entry_size: 0x18,
+        }))
+    );
+}
+
+#[test_case]
+fn relocations_add_load_bias() {
+    let no_symbols = |index| Err(ElfError::InvalidSymbol(index));
+    let rela = ELF64Rela {
+        offset: 0x2010,
+        info: R_X86_64_RELATIVE as u64,
+        addend: 0x1130,
+    };
+    assert_eq!(
+        rela.resolve(0x5555_5555_4000, no_symbols),
+        Ok(Some((0x5555_5555_6010, 0x5555_5555_5130)))
+    );
+
+    let symbol = |index| Ok(0x5555_5555_5000 + index as u64 * 0x10);
+    let absolute = ELF64Rela {
+        offset: 0x2018,
+        info: (3 << 32) | R_X86_64_64 as u64,
+        addend: 8,
+    };
+    assert_eq!(
+        absolute.resolve(0x5555_5555_4000, symbol),
+        Ok(Some((0x5555_5555_6018, 0x5555_5555_5038)))
+    );
+    let slot = ELF64Rela {
+        offset: 0x2020,
+        info: (3 << 32) | R_X86_64_JUMP_SLOT as u64,
+        addend: 0,
+    };
+    assert_eq!(
+        slot.resolve(0x5555_5555_4000, symbol),
+        Ok(Some((0x5555_5555_6020, 0x5555_5555_5030)))
+    );
+
+    let irelative = ELF64Rela {
+        offset: 0x2028,
+        info: R_X86_64_IRELATIVE as u64,
+        addend: 0x1200,
+    };
+    assert_eq!(irelative.resolve(0x5555_5555_4000, no_symbols), Ok(None));
+
+    let tls = ELF64Rela {
+        offset: 0x2030,
+        info: (1 << 32) | 16,
+        addend: 0,
+    };
+    assert_eq!(
+        tls.resolve(0x5555_5555_4000, symbol),
+        Err(ElfError::UnsupportedRelocation(16))
+    );
+}
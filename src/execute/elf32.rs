@@ -0,0 +1,255 @@
+use alloc::vec::Vec;
+use anyhow::anyhow;
+
+use crate::{
+    file_system::{File, FileSystem, StorageFormat},
+    memory::{allocator::BootInfoFrameAllocator, with_mapper},
+    serial_println,
+};
+
+use super::{
+    auxv::{build_initial_stack, AT_ENTRY, AT_PAGESZ, AT_PHENT, AT_PHNUM},
+    elf64::{
+        load_segments, map_stack, read_file, segment_regions, stack_region,
+        ELF64ProgramHeader, ElfError, ELF_CLASS_32, ELF_DATA_LITTLE_ENDIAN,
+        ELF_MACHINE_I386, ELF_MAGIC, ELF_TYPE_EXECUTABLE, PT_INTERP, PT_TLS,
+    },
+    enter_user_mode,
+    process::{Process, CURRENT_PROCESS},
+    Executor, UserContext,
+};
+
+/// 32-bit programs have to live below 4 GiB.
+pub const COMPAT_SPACE_END: u64 = 0x1_0000_0000;
+
+#[derive(Debug, Clone)]
+#[repr(C, packed)]
+pub struct ELF32Header {
+    identity: [u8; 16],             // 0x00
+    object_type: u16,               // 0x10
+    arch: u16,                      // 0x12
+    version2: u32,                  // 0x14
+    instruction_pointer_entry: u32, // 0x18
+    program_header_entry: u32,      // 0x1c
+    section_header_entry: u32,      // 0x20
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_entries: u16,
+    section_header_entry_size: u16,
+    section_header_entries: u16,
+    section_name_index: u16,
+}
+
+impl ELF32Header {
+    pub fn entry_point(&self) -> u32 {
+        self.instruction_pointer_entry
+    }
+
+    /// Checks that the program header table lies inside a file of
+    /// `file_size` bytes and that its entries are large enough to hold an
+    /// [`ELF32ProgramHeader`].
+    pub fn check_program_headers(
+        &self,
+        file_size: usize,
+    ) -> Result<(), ElfError> {
+        let entry_size = self.program_header_size;
+        if (entry_size as usize) < core::mem::size_of::<ELF32ProgramHeader>() {
+            return Err(ElfError::InvalidProgramHeaderSize(entry_size));
+        }
+        let offset = self.program_header_entry as u64;
+        let count = self.program_header_entries;
+        let end = offset + count as u64 * entry_size as u64;
+        if end > file_size as u64 {
+            return Err(ElfError::ProgramHeadersOutOfBounds {
+                offset,
+                count,
+                file_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&[u8]> for ELF32Header {
+    type Error = ElfError;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let expected = core::mem::size_of::<ELF32Header>();
+        if value.len() < expected {
+            return Err(ElfError::Truncated {
+                expected,
+                found: value.len(),
+            });
+        }
+        let header =
+            unsafe { core::ptr::read(value.as_ptr() as *const ELF32Header) };
+
+        let identity = header.identity;
+        if identity[..4] != ELF_MAGIC {
+            return Err(ElfError::InvalidMagic([
+                identity[0],
+                identity[1],
+                identity[2],
+                identity[3],
+            ]));
+        }
+        if identity[4] != ELF_CLASS_32 {
+            return Err(ElfError::UnsupportedClass(identity[4]));
+        }
+        if identity[5] != ELF_DATA_LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedEndianess(identity[5]));
+        }
+        if header.arch != ELF_MACHINE_I386 {
+            return Err(ElfError::UnsupportedMachine(header.arch));
+        }
+        if header.object_type != ELF_TYPE_EXECUTABLE {
+            return Err(ElfError::UnsupportedType(header.object_type));
+        }
+        Ok(header)
+    }
+}
+
+/// 32-bit program header. Note that the flags come after the sizes here,
+/// unlike in [`ELF64ProgramHeader`].
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct ELF32ProgramHeader {
+    segment_type: u32,
+    offset: u32,
+    virt_addr: u32,
+    phys_addr: u32,
+    file_image_size: u32,
+    memory_size: u32,
+    segment_flags: u32,
+    alignment: u32,
+}
+
+impl TryFrom<&[u8]> for ELF32ProgramHeader {
+    type Error = ElfError;
+    fn try_from(section: &[u8]) -> Result<Self, Self::Error> {
+        let expected = core::mem::size_of::<ELF32ProgramHeader>();
+        if section.len() < expected {
+            return Err(ElfError::Truncated {
+                expected,
+                found: section.len(),
+            });
+        }
+        Ok(unsafe {
+            core::ptr::read(section.as_ptr() as *const ELF32ProgramHeader)
+        })
+    }
+}
+
+/// Widens a 32-bit program header so the 64-bit segment loader can map it.
+impl From<ELF32ProgramHeader> for ELF64ProgramHeader {
+    fn from(header: ELF32ProgramHeader) -> Self {
+        ELF64ProgramHeader {
+            segment_type: header.segment_type,
+            segment_flags: header.segment_flags,
+            offset: header.offset as u64,
+            virt_addr: header.virt_addr as u64,
+            phys_addr: header.phys_addr as u64,
+            file_image_size: header.file_image_size as u64,
+            memory_size: header.memory_size as u64,
+            alignment: header.alignment as u64,
+        }
+    }
+}
+
+/// Reads the ELF header and the program header table of a 32-bit `file`.
+/// The program headers are widened to [`ELF64ProgramHeader`]s.
+pub fn get_elf32<'a, T: StorageFormat<'a>>(
+    fs: &FileSystem<'a, T>,
+    file: &File,
+) -> Result<(ELF32Header, Vec<ELF64ProgramHeader>), ElfError> {
+    let file_size = file.size as usize;
+    let mut header_bytes = [0u8; core::mem::size_of::<ELF32Header>()];
+    if file_size < header_bytes.len() {
+        return Err(ElfError::Truncated {
+            expected: header_bytes.len(),
+            found: file_size,
+        });
+    }
+    read_file(fs, file, &mut header_bytes, 0)?;
+    let header = ELF32Header::try_from(header_bytes.as_slice())?;
+    header.check_program_headers(file_size)?;
+
+    let size = header.program_header_size as usize;
+    let count = header.program_header_entries as usize;
+    let mut table = alloc::vec![0u8; size * count];
+    read_file(fs, file, &mut table, header.program_header_entry as usize)?;
+
+    let mut program_headers = Vec::with_capacity(count);
+    for entry in table.chunks_exact(size) {
+        let program_header: ELF64ProgramHeader =
+            ELF32ProgramHeader::try_from(entry)?.into();
+        program_header.check_bounds(file_size)?;
+        program_headers.push(program_header);
+    }
+    Ok((header, program_headers))
+}
+
+/// Statically linked i386 executables, run in compatibility mode.
+pub struct ELF32;
+
+impl Executor for ELF32 {
+    fn load_executable<'a, T: StorageFormat<'a>>(
+        fs: &'static FileSystem<'a, T>,
+        file: &File,
+        args: &[&str],
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> anyhow::Result<()> {
+        let (header, program_headers) = get_elf32(fs, file)?;
+        if program_headers.iter().any(|p| p.segment_type == PT_INTERP) {
+            return Err(anyhow!(
+                "Dynamically linked 32-bit executables are not supported"
+            ));
+        }
+        if program_headers.iter().any(|p| p.segment_type == PT_TLS) {
+            return Err(anyhow!("TLS in 32-bit executables is not supported"));
+        }
+
+        let segments = load_segments(&program_headers, 0)?;
+        for segment in &segments {
+            if segment.virt_addr + segment.memory_size > COMPAT_SPACE_END {
+                return Err(ElfError::SegmentOutOfBounds {
+                    offset: segment.offset,
+                    file_image_size: segment.file_image_size,
+                    memory_size: segment.memory_size,
+                }
+                .into());
+            }
+        }
+        let regions = segment_regions(fs, file, &segments, frame_allocator)?;
+
+        let (stack_top, stack_size, stack_bottom) =
+            with_mapper(|mapper| map_stack(mapper, frame_allocator))?;
+        let mut process = Process::new(&file.path);
+        for region in regions {
+            process.add_vma(region);
+        }
+        process.add_vma(stack_region(stack_bottom));
+        let auxv = [
+            (AT_PHENT, header.program_header_size as u64),
+            (AT_PHNUM, header.program_header_entries as u64),
+            (AT_PAGESZ, 4096),
+            (AT_ENTRY, header.entry_point() as u64),
+        ];
+        let stack = unsafe {
+            core::slice::from_raw_parts_mut(
+                stack_bottom.as_mut_ptr::<u8>(),
+                stack_size as usize,
+            )
+        };
+        let mut argv = alloc::vec![file.path.as_str()];
+        argv.extend_from_slice(args);
+        let esp =
+            build_initial_stack(stack, stack_top.as_u64(), &argv, &auxv, 4)?;
+
+        let user_context =
+            UserContext::compat(header.entry_point(), esp as u32);
+        serial_println!("{:#x?}", user_context);
+        *CURRENT_PROCESS.lock() = Some(process);
+        unsafe { enter_user_mode(&user_context) }
+    }
+}
@@ -1,19 +1,33 @@
-use alloc::vec::Vec;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use anyhow::anyhow;
+use core::fmt::Display;
 use x86_64::{
     structures::paging::{
-        page_table::PageTableLevel, FrameAllocator, OffsetPageTable, Page,
-        PageTableFlags, Size4KiB,
+        page_table::PageTableLevel, FrameAllocator, FrameDeallocator,
+        OffsetPageTable, Page, PageSize, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
 use crate::{
-    file_system::{File, FileSystem, StorageFormat},
-    memory::allocator::BootInfoFrameAllocator,
+    file_system::{File, FileSource, FileSystem, StorageFormat},
+    memory::{allocator::BootInfoFrameAllocator, kernel_ranges, with_mapper},
     serial_println,
 };
 
-use super::{enter_user_mode, Executor, UserContext};
+use super::{
+    auxv::{
+        build_initial_stack, AT_BASE, AT_ENTRY, AT_PAGESZ, AT_PHDR, AT_PHENT,
+        AT_PHNUM,
+    },
+    dynamic::relocate,
+    enter_user_mode,
+    paging::populate,
+    process::{page_regions, Backing, MemoryRegion, Process, CURRENT_PROCESS},
+    symbols::{load_symbols, USER_SYMBOLS},
+    tls::{allocate_tls, set_fs_base, TlsTemplate, TLS_FLAGS, USER_TLS_BASE},
+    Executor, UserContext,
+};
 
 #[derive(Debug, Clone)]
 #[repr(C, packed)]
@@ -65,33 +79,136 @@ impl ELF64Header {
     }
 }
 
-impl From<&[u8]> for ELF64Header {
-    fn from(value: &[u8]) -> Self {
-        if value.len() != core::mem::size_of::<ELF64Header>() {
-            panic!(
-                "Value: {:?}, Size: {:?}",
-                value.len(),
-                core::mem::size_of::<ELF64Header>()
-            );
+impl ELF64Header {
+    pub fn entry_point(&self) -> u64 {
+        self.instruction_pointer_entry
+    }
+
+    /// `ET_DYN` executables are linked at address zero and have to be moved
+    /// to a load base chosen by the kernel.
+    pub fn is_position_independent(&self) -> bool {
+        self.object_type == ELF_TYPE_SHARED
+    }
+
+    /// Offset, entry size and number of entries of the section header
+    /// table.
+    pub fn section_headers(&self) -> (u64, u16, u16) {
+        (
+            self.section_header_entry,
+            self.section_header_entry_size,
+            self.section_header_entries,
+        )
+    }
+
+    /// Offset added to every virtual address of the executable. Segment
+    /// alignments above [`MAX_SEGMENT_ALIGNMENT`] are rejected.
+    pub fn load_bias(
+        &self,
+        program_headers: &[ELF64ProgramHeader],
+        base: u64,
+    ) -> Result<u64, ElfError> {
+        if !self.is_position_independent() {
+            return Ok(0);
+        }
+        let alignment = program_headers
+            .iter()
+            .filter(|p| p.segment_type == PT_LOAD)
+            .map(|p| p.alignment)
+            .filter(|align| align.is_power_of_two())
+            .max()
+            .unwrap_or(1)
+            .max(4096);
+        if alignment > MAX_SEGMENT_ALIGNMENT {
+            return Err(ElfError::AlignmentTooLarge(alignment));
+        }
+        let lowest = program_headers
+            .iter()
+            .filter(|p| p.segment_type == PT_LOAD)
+            .map(|p| p.virt_addr & !(alignment - 1))
+            .min()
+            .unwrap_or(0);
+        Ok(choose_load_base(base, alignment).wrapping_sub(lowest))
+    }
+
+    /// Checks that the program header table lies inside a file of
+    /// `file_size` bytes and that its entries are large enough to hold an
+    /// [`ELF64ProgramHeader`].
+    pub fn check_program_headers(
+        &self,
+        file_size: usize,
+    ) -> Result<(), ElfError> {
+        let entry_size = self.program_header_size;
+        if (entry_size as usize) < core::mem::size_of::<ELF64ProgramHeader>() {
+            return Err(ElfError::InvalidProgramHeaderSize(entry_size));
+        }
+        let offset = self.program_header_entry;
+        let count = self.program_header_entries;
+        let end = (count as u64)
+            .checked_mul(entry_size as u64)
+            .and_then(|size| size.checked_add(offset));
+        match end {
+            Some(end) if end <= file_size as u64 => Ok(()),
+            _ => Err(ElfError::ProgramHeadersOutOfBounds {
+                offset,
+                count,
+                file_size,
+            }),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for ELF64Header {
+    type Error = ElfError;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let expected = core::mem::size_of::<ELF64Header>();
+        if value.len() < expected {
+            return Err(ElfError::Truncated {
+                expected,
+                found: value.len(),
+            });
         }
         let header =
             unsafe { core::ptr::read(value.as_ptr() as *const ELF64Header) };
         //header.flip_endianess();
-        header
+
+        let identity = header.identity;
+        if identity[..4] != ELF_MAGIC {
+            return Err(ElfError::InvalidMagic([
+                identity[0],
+                identity[1],
+                identity[2],
+                identity[3],
+            ]));
+        }
+        if identity[4] != ELF_CLASS_64 {
+            return Err(ElfError::UnsupportedClass(identity[4]));
+        }
+        if identity[5] != ELF_DATA_LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedEndianess(identity[5]));
+        }
+        if header.arch != ELF_MACHINE_X86_64 {
+            return Err(ElfError::UnsupportedMachine(header.arch));
+        }
+        let object_type = header.object_type;
+        if object_type != ELF_TYPE_EXECUTABLE && object_type != ELF_TYPE_SHARED
+        {
+            return Err(ElfError::UnsupportedType(object_type));
+        }
+        Ok(header)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct ELF64ProgramHeader {
-    segment_type: u32,
-    segment_flags: u32,
-    offset: u64,
-    virt_addr: u64,
-    phys_addr: u64,
-    file_image_size: u64,
-    memory_size: u64,
-    alignment: u64,
+    pub(super) segment_type: u32,
+    pub(super) segment_flags: u32,
+    pub(super) offset: u64,
+    pub(super) virt_addr: u64,
+    pub(super) phys_addr: u64,
+    pub(super) file_image_size: u64,
+    pub(super) memory_size: u64,
+    pub(super) alignment: u64,
 }
 
 impl ELF64ProgramHeader {
@@ -107,18 +224,75 @@ impl ELF64ProgramHeader {
     }
 }
 
-impl From<&[u8]> for ELF64ProgramHeader {
-    fn from(section: &[u8]) -> Self {
+impl ELF64ProgramHeader {
+    pub fn virt_addr(&self) -> u64 {
+        self.virt_addr
+    }
+
+    pub fn memory_size(&self) -> u64 {
+        self.memory_size
+    }
+
+    pub fn flags(&self) -> ELF64SegmentFlags {
+        ELF64SegmentFlags::from_bits_truncate(self.segment_flags)
+    }
+
+    /// Page table flags the segment should end up with once it is loaded:
+    /// user accessible, writable only if the segment is writable and
+    /// no-execute unless the segment is executable.
+    pub fn page_table_flags(&self) -> PageTableFlags {
+        let segment_flags = self.flags();
+        let mut flags =
+            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if segment_flags.contains(ELF64SegmentFlags::WRITABLE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment_flags.contains(ELF64SegmentFlags::EXACUTABLE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    /// Checks that the file image of this segment lies inside a file of
+    /// `file_size` bytes and fits in its memory image.
+    pub fn check_bounds(&self, file_size: usize) -> Result<(), ElfError> {
+        let offset = self.offset;
+        let file_image_size = self.file_image_size;
+        let memory_size = self.memory_size;
+        let in_file = offset
+            .checked_add(file_image_size)
+            .is_some_and(|end| end <= file_size as u64);
+        if !in_file || file_image_size > memory_size {
+            return Err(ElfError::SegmentOutOfBounds {
+                offset,
+                file_image_size,
+                memory_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&[u8]> for ELF64ProgramHeader {
+    type Error = ElfError;
+    fn try_from(section: &[u8]) -> Result<Self, Self::Error> {
+        let expected = core::mem::size_of::<ELF64ProgramHeader>();
+        if section.len() < expected {
+            return Err(ElfError::Truncated {
+                expected,
+                found: section.len(),
+            });
+        }
         let header = unsafe {
             core::ptr::read(section.as_ptr() as *const ELF64ProgramHeader)
         };
         //header.flip_endianess();
-        header
+        Ok(header)
     }
 }
 
 bitflags::bitflags! {
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Copy)]
     pub struct ELF64SegmentFlags: u32 {
         const EXACUTABLE = 0x1;
         const WRITABLE = 0x2;
@@ -126,129 +300,777 @@ bitflags::bitflags! {
     }
 }
 
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+pub const ELF_CLASS_32: u8 = 1;
+pub const ELF_CLASS_64: u8 = 2;
+pub const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+pub const ELF_MACHINE_I386: u16 = 0x03;
+pub const ELF_MACHINE_X86_64: u16 = 0x3e;
+pub const ELF_TYPE_EXECUTABLE: u16 = 2;
+pub const ELF_TYPE_SHARED: u16 = 3;
+
+/// First address above the lower half, user segments must end below it.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+/// Top of the user stack set up by [`map_stack`].
+pub const USER_STACK_TOP: u64 = 0x0000_8000_0000;
+/// Part of the stack mapped up front, it grows on faults from there.
+pub const USER_STACK_SIZE: u64 = 16 * 1024;
+pub const USER_STACK_MAX_SIZE: u64 = 8 * 1024 * 1024;
+pub const USER_STACK_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE);
+/// Where position-independent executables are loaded.
+pub const PIE_LOAD_BASE: u64 = 0x5555_5555_4000;
+/// Largest `p_align` of a position-independent executable.
+pub const MAX_SEGMENT_ALIGNMENT: u64 = 1 << 30;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_NOTE: u32 = 4;
+pub const PT_PHDR: u32 = 6;
+pub const PT_TLS: u32 = 7;
+pub const PT_GNU_STACK: u32 = 0x6474_e551;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated {
+        expected: usize,
+        found: usize,
+    },
+    InvalidMagic([u8; 4]),
+    UnsupportedClass(u8),
+    UnsupportedEndianess(u8),
+    UnsupportedMachine(u16),
+    UnsupportedType(u16),
+    InvalidProgramHeaderSize(u16),
+    AlignmentTooLarge(u64),
+    ProgramHeadersOutOfBounds {
+        offset: u64,
+        count: u16,
+        file_size: usize,
+    },
+    SegmentOutOfBounds {
+        offset: u64,
+        file_image_size: u64,
+        memory_size: u64,
+    },
+    MisalignedSegment {
+        virt_addr: u64,
+        offset: u64,
+        alignment: u64,
+    },
+    OverlappingSegments(u64, u64),
+    /// Address of a page shared by a writable and an executable segment.
+    WritableExecutablePage(u64),
+    ReadFailed {
+        offset: usize,
+        len: usize,
+    },
+    InvalidDynamicSection(u64),
+    UnsupportedRelocation(u32),
+    RelocationOutOfBounds(u64),
+    InvalidSymbol(u32),
+    InvalidInterpreter,
+    InvalidSection(u32),
+    InvalidTls,
+    /// No free frame was left to map the image.
+    OutOfMemory,
+    /// Address of a page that could not be mapped.
+    MapFailed(u64),
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ElfError::Truncated { expected, found } => write!(
+                f,
+                "ELF data truncated: expected {} bytes, found {}",
+                expected, found
+            ),
+            ElfError::InvalidMagic(magic) => {
+                write!(f, "Invalid ELF magic: {:02x?}", magic)
+            }
+            ElfError::UnsupportedClass(class) => {
+                write!(f, "Unsupported ELF class: {}", class)
+            }
+            ElfError::UnsupportedEndianess(data) => {
+                write!(f, "Unsupported ELF endianess: {}", data)
+            }
+            ElfError::UnsupportedMachine(machine) => {
+                write!(f, "Unsupported ELF machine: {:#x}", machine)
+            }
+            ElfError::UnsupportedType(object_type) => {
+                write!(f, "Unsupported ELF type: {}", object_type)
+            }
+            ElfError::InvalidProgramHeaderSize(size) => {
+                write!(f, "Invalid program header size: {}", size)
+            }
+            ElfError::AlignmentTooLarge(alignment) => {
+                write!(f, "Segment alignment {:#x} is too large", alignment)
+            }
+            ElfError::ProgramHeadersOutOfBounds {
+                offset,
+                count,
+                file_size,
+            } => write!(
+                f,
+                "{} program headers at {:#x} exceed file size {}",
+                count, offset, file_size
+            ),
+            ElfError::SegmentOutOfBounds {
+                offset,
+                file_image_size,
+                memory_size,
+            } => write!(
+                f,
+                "Segment at {:#x} (file size {}, memory size {}) is out of bounds",
+                offset, file_image_size, memory_size
+            ),
+            ElfError::MisalignedSegment {
+                virt_addr,
+                offset,
+                alignment,
+            } => write!(
+                f,
+                "Segment at {:#x} (offset {:#x}) is not aligned to {:#x}",
+                virt_addr, offset, alignment
+            ),
+            ElfError::OverlappingSegments(first, second) => write!(
+                f,
+                "Segments at {:#x} and {:#x} overlap",
+                first, second
+            ),
+            ElfError::WritableExecutablePage(addr) => write!(
+                f,
+                "Page at {:#x} would be both writable and executable",
+                addr
+            ),
+            ElfError::ReadFailed { offset, len } => write!(
+                f,
+                "Failed to read {} bytes at offset {:#x}",
+                len, offset
+            ),
+            ElfError::InvalidDynamicSection(addr) => {
+                write!(f, "Invalid dynamic section at {:#x}", addr)
+            }
+            ElfError::UnsupportedRelocation(kind) => {
+                write!(f, "Unsupported relocation type: {}", kind)
+            }
+            ElfError::RelocationOutOfBounds(addr) => {
+                write!(f, "Relocation at {:#x} is outside the image", addr)
+            }
+            ElfError::InvalidSymbol(index) => {
+                write!(f, "Invalid or undefined symbol: {}", index)
+            }
+            ElfError::InvalidInterpreter => {
+                write!(f, "Invalid program interpreter")
+            }
+            ElfError::InvalidSection(index) => {
+                write!(f, "Invalid section header: {}", index)
+            }
+            ElfError::InvalidTls => write!(f, "Invalid TLS segment"),
+            ElfError::OutOfMemory => write!(f, "Out of memory"),
+            ElfError::MapFailed(addr) => {
+                write!(f, "Failed to map page at {:#x}", addr)
+            }
+        }
+    }
+}
+
+impl core::error::Error for ElfError {}
+
+/// Reads the ELF header and the program header table of `file` without
+/// loading the rest of the file.
 pub fn get_elf64<'a, T: StorageFormat<'a>>(
     fs: &FileSystem<'a, T>,
     file: &File,
-) -> anyhow::Result<(ELF64Header, Vec<ELF64ProgramHeader>)> {
-    let header_sector = fs.get_content(file);
-    let header = ELF64Header::from(&header_sector[..0x40]);
+) -> Result<(ELF64Header, Vec<ELF64ProgramHeader>), ElfError> {
+    let file_size = file.size as usize;
+    let mut header_bytes = [0u8; core::mem::size_of::<ELF64Header>()];
+    if file_size < header_bytes.len() {
+        return Err(ElfError::Truncated {
+            expected: header_bytes.len(),
+            found: file_size,
+        });
+    }
+    read_file(fs, file, &mut header_bytes, 0)?;
+    let header = ELF64Header::try_from(header_bytes.as_slice())?;
+    header.check_program_headers(file_size)?;
 
     let size = header.program_header_size as usize;
-    let mut entry = header.program_header_entry as usize;
-    let mut program_headers = Vec::new();
+    let count = header.program_header_entries as usize;
+    let mut table = alloc::vec![0u8; size * count];
+    read_file(fs, file, &mut table, header.program_header_entry as usize)?;
 
-    for _ in 0..header.program_header_entries {
-        let program_header =
-            ELF64ProgramHeader::from(&header_sector[entry..entry + size]);
+    let mut program_headers = Vec::with_capacity(count);
+    for entry in table.chunks_exact(size) {
+        let program_header = ELF64ProgramHeader::try_from(entry)?;
+        program_header.check_bounds(file_size)?;
         program_headers.push(program_header);
-        entry += size;
     }
     Ok((header, program_headers))
 }
 
-pub fn map_program_header(
-    program_header: &ELF64ProgramHeader,
+pub(super) fn read_file<'a, T: StorageFormat<'a>>(
+    fs: &FileSystem<'a, T>,
+    file: &File,
+    buffer: &mut [u8],
+    offset: usize,
+) -> Result<(), ElfError> {
+    fs.read_bytes(file, buffer, offset)
+        .map_err(|_| ElfError::ReadFailed {
+            offset,
+            len: buffer.len(),
+        })
+}
+
+/// Keeps only the `PT_LOAD` segments, moved by `load_bias` and sorted by
+/// virtual address, and checks that they can be placed in user memory:
+/// `p_vaddr` and `p_offset` must agree modulo `p_align`, no two segments
+/// may overlap and none may overlap the kernel.
+pub fn load_segments(
+    program_headers: &[ELF64ProgramHeader],
+    load_bias: u64,
+) -> Result<Vec<ELF64ProgramHeader>, ElfError> {
+    let mut segments: Vec<ELF64ProgramHeader> = program_headers
+        .iter()
+        .filter(|p| p.segment_type == PT_LOAD && p.memory_size > 0)
+        .copied()
+        .collect();
+    segments.sort_by_key(|p| p.virt_addr);
+
+    for segment in &mut segments {
+        let alignment = segment.alignment;
+        let virt_addr = segment.virt_addr;
+        let offset = segment.offset;
+        if alignment > 1
+            && (!alignment.is_power_of_two()
+                || virt_addr % alignment != offset % alignment)
+        {
+            return Err(ElfError::MisalignedSegment {
+                virt_addr,
+                offset,
+                alignment,
+            });
+        }
+        let end = virt_addr
+            .checked_add(load_bias)
+            .and_then(|start| start.checked_add(segment.memory_size));
+        let start = virt_addr.wrapping_add(load_bias);
+        let in_user_space = end.is_some_and(|end| {
+            end <= USER_SPACE_END
+                && !kernel_ranges()
+                    .iter()
+                    .any(|range| start < range.end && range.start < end)
+        });
+        if !in_user_space {
+            return Err(ElfError::SegmentOutOfBounds {
+                offset,
+                file_image_size: segment.file_image_size,
+                memory_size: segment.memory_size,
+            });
+        }
+        segment.virt_addr = start;
+    }
+    for pair in segments.windows(2) {
+        let end = pair[0].virt_addr + pair[0].memory_size;
+        if end > pair[1].virt_addr {
+            return Err(ElfError::OverlappingSegments(
+                pair[0].virt_addr,
+                pair[1].virt_addr,
+            ));
+        }
+    }
+    Ok(segments)
+}
+
+/// Picks the load base for a position-independent image near `base`. With
+/// the `aslr` feature the base is randomized using `rdrand`, within
+/// `ASLR_RANGE` bytes above `base`.
+fn choose_load_base(base: u64, alignment: u64) -> u64 {
+    #[cfg(feature = "aslr")]
+    {
+        use x86_64::instructions::random::RdRand;
+
+        const ASLR_SLOTS: u64 = 1 << 16;
+        const ASLR_RANGE: u64 = 1 << 40;
+        if let Some(random) = RdRand::new().and_then(RdRand::get_u64) {
+            let slots = ASLR_SLOTS.min(ASLR_RANGE / alignment);
+            let base = base + (random % slots) * alignment;
+            return base & !(alignment - 1);
+        }
+    }
+    (base + alignment - 1) & !(alignment - 1)
+}
+
+/// Every page touched by `segments`, with the final flags it should get.
+/// A page shared by two segments gets the union of their permissions,
+/// unless that would make it both writable and executable.
+pub fn segment_pages(
+    segments: &[ELF64ProgramHeader],
+) -> Result<Vec<(Page<Size4KiB>, PageTableFlags)>, ElfError> {
+    let mut pages: Vec<(Page<Size4KiB>, PageTableFlags)> = Vec::new();
+    for segment in segments {
+        let flags = segment.page_table_flags();
+        let start_addr = VirtAddr::new(segment.virt_addr);
+        let end_addr = start_addr + segment.memory_size;
+        let start_page = Page::containing_address(start_addr);
+        let end_page = Page::containing_address(end_addr - 1u64);
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            match pages.last_mut() {
+                Some((last, last_flags)) if *last == page => {
+                    *last_flags = merge_flags(*last_flags, flags).ok_or(
+                        ElfError::WritableExecutablePage(
+                            page.start_address().as_u64(),
+                        ),
+                    )?;
+                }
+                _ => pages.push((page, flags)),
+            }
+        }
+    }
+    Ok(pages)
+}
+
+/// The union of two page permissions, `None` if it would be W+X.
+fn merge_flags(a: PageTableFlags, b: PageTableFlags) -> Option<PageTableFlags> {
+    let no_execute = (a & b) & PageTableFlags::NO_EXECUTE;
+    let merged = ((a | b) - PageTableFlags::NO_EXECUTE) | no_execute;
+    let writable = merged.contains(PageTableFlags::WRITABLE);
+    (!writable || merged.contains(PageTableFlags::NO_EXECUTE)).then_some(merged)
+}
+
+/// Maps and zeroes the segment pages kernel-writable so their contents can
+/// be copied in. Call [`protect_segment_pages`] afterwards to apply the
+/// segments' own permissions. If a page can't be mapped, the ones mapped
+/// so far are unmapped again.
+pub fn map_segment_pages(
+    pages: &[(Page<Size4KiB>, PageTableFlags)],
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), ElfError> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE;
+    for (mapped, (page, _)) in pages.iter().enumerate() {
+        if let Err(e) = map_user_page(*page, flags, mapper, frame_allocator) {
+            let pages = pages[..mapped].iter().map(|(page, _)| *page);
+            unmap_user_pages(pages, mapper, frame_allocator);
+            return Err(e);
+        }
+        unsafe {
+            core::ptr::write_bytes(
+                page.start_address().as_mut_ptr::<u8>(),
+                0,
+                page.size() as usize,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Maps `page` to a fresh frame.
+pub(super) fn map_user_page(
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), ElfError> {
+    use x86_64::structures::paging::mapper::Mapper;
+
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(ElfError::OutOfMemory)?;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(ElfError::MapFailed(page.start_address().as_u64()))
+        }
+    }
+}
+
+/// Undoes [`map_user_page`] for `pages`.
+pub(super) fn unmap_user_pages(
+    pages: impl Iterator<Item = Page<Size4KiB>>,
     mapper: &mut OffsetPageTable,
     frame_allocator: &mut BootInfoFrameAllocator,
 ) {
-    use x86_64::structures::paging::{
-        mapper::Mapper, FrameAllocator, Page, PageTableFlags,
-    };
+    use x86_64::structures::paging::mapper::Mapper;
 
-    let start_addr = VirtAddr::new(program_header.virt_addr);
-    let end_addr = start_addr + program_header.memory_size;
-    let start_page = Page::containing_address(start_addr);
-    let end_page = Page::containing_address(end_addr - 1);
+    for page in pages {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
 
-    for page in Page::range_inclusive(start_page, end_page) {
-        let frame = frame_allocator.allocate_frame().expect("no frame");
-        let mut flags = PageTableFlags::PRESENT;
-        flags |= PageTableFlags::WRITABLE;
+/// Restricts the segment pages to the permissions in their
+/// [`ELF64SegmentFlags`].
+pub fn protect_segment_pages(
+    pages: &[(Page<Size4KiB>, PageTableFlags)],
+    mapper: &mut OffsetPageTable,
+) -> anyhow::Result<()> {
+    use x86_64::structures::paging::mapper::Mapper;
+
+    for (page, flags) in pages {
         unsafe {
             mapper
-                .map_to(page, frame, flags, frame_allocator)
-                .expect("map failed")
+                .update_flags(*page, *flags)
+                .map_err(|e| anyhow!("update flags failed: {:?}", e))?
                 .flush();
         }
     }
+    Ok(())
 }
 
 pub fn map_stack(
     mapper: &mut OffsetPageTable,
     frame_allocator: &mut BootInfoFrameAllocator,
-) -> anyhow::Result<(VirtAddr, u64, VirtAddr)> {
-    use x86_64::{
-        structures::paging::{
-            mapper::Mapper, FrameAllocator, Page, PageTableFlags,
-        },
-        VirtAddr,
-    };
-
-    let stack_size: u64 = 16 * 1024;
-    let stack_top = VirtAddr::new(0x0000_8000_0000);
+) -> Result<(VirtAddr, u64, VirtAddr), ElfError> {
+    let stack_size: u64 = USER_STACK_SIZE;
+    let stack_top = VirtAddr::new(USER_STACK_TOP);
     let stack_bottom = stack_top - stack_size;
     let start_page = Page::containing_address(stack_bottom);
     let end_page = Page::containing_address(stack_top - 1u64);
 
     for page in Page::range_inclusive(start_page, end_page) {
-        let frame = frame_allocator.allocate_frame().expect("no frame");
-        let flags = PageTableFlags::WRITABLE
-            | PageTableFlags::PRESENT
-            | PageTableFlags::USER_ACCESSIBLE;
-        unsafe {
-            mapper
-                .map_to(page, frame, flags, frame_allocator)
-                .expect("map failed")
-                .flush();
+        let mapped =
+            map_user_page(page, USER_STACK_FLAGS, mapper, frame_allocator);
+        if let Err(e) = mapped {
+            let pages = Page::range(start_page, page);
+            unmap_user_pages(pages, mapper, frame_allocator);
+            return Err(e);
         }
     }
 
     Ok((stack_top, stack_size, stack_bottom))
 }
 
-pub fn load_program_header(
+/// The VMA of a stack set up by [`map_stack`], which may grow down to
+/// [`USER_STACK_MAX_SIZE`].
+pub fn stack_region(stack_bottom: VirtAddr) -> MemoryRegion {
+    MemoryRegion::new(
+        stack_bottom.as_u64(),
+        USER_STACK_TOP,
+        USER_STACK_FLAGS,
+        Backing::Stack {
+            limit: USER_STACK_TOP - USER_STACK_MAX_SIZE,
+        },
+    )
+}
+
+/// VMAs that page the segments in from `file` when they are first
+/// touched.
+fn file_regions(
+    fs: &'static dyn FileSource,
+    file: &File,
+    segments: &[ELF64ProgramHeader],
+) -> Vec<MemoryRegion> {
+    let file = Arc::new(file.clone());
+    segments
+        .iter()
+        .map(|segment| {
+            let start = segment.virt_addr;
+            let end = start + segment.memory_size;
+            MemoryRegion::new(
+                start & !(Size4KiB::SIZE - 1),
+                end.next_multiple_of(Size4KiB::SIZE),
+                segment.page_table_flags(),
+                Backing::File {
+                    fs,
+                    file: file.clone(),
+                    offset: segment.offset,
+                    start,
+                    size: segment.file_image_size,
+                },
+            )
+        })
+        .collect()
+}
+
+/// The VMAs of `segments` of `file`. They are paged in from the file on
+/// first access, unless two of them share a page; then all are loaded
+/// right away.
+pub fn segment_regions<'a, T: StorageFormat<'a>>(
+    fs: &'static FileSystem<'a, T>,
+    file: &File,
+    segments: &[ELF64ProgramHeader],
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> anyhow::Result<Vec<MemoryRegion>> {
+    if segments_share_pages(segments) {
+        load_now(fs, file, segments, frame_allocator, || Ok(()))
+    } else {
+        Ok(file_regions(fs, file, segments))
+    }
+}
+
+/// Maps `segments` and copies them in from `file`, then runs `fixup` on
+/// the still writable image before the segment permissions are applied.
+fn load_now<'a, T: StorageFormat<'a>>(
+    fs: &FileSystem<'a, T>,
+    file: &File,
+    segments: &[ELF64ProgramHeader],
+    frame_allocator: &mut BootInfoFrameAllocator,
+    fixup: impl FnOnce() -> Result<(), ElfError>,
+) -> anyhow::Result<Vec<MemoryRegion>> {
+    let pages = segment_pages(segments)?;
+    with_mapper(|mapper| map_segment_pages(&pages, mapper, frame_allocator))?;
+    for segment in segments {
+        load_program_header(fs, file, segment)?;
+    }
+    fixup()?;
+    with_mapper(|mapper| protect_segment_pages(&pages, mapper))?;
+    Ok(page_regions(&pages))
+}
+
+/// Whether two segments share a page, which then can't be paged in from
+/// either one alone.
+fn segments_share_pages(segments: &[ELF64ProgramHeader]) -> bool {
+    segments.windows(2).any(|pair| {
+        let end = pair[0].virt_addr + pair[0].memory_size;
+        (end - 1) / Size4KiB::SIZE == pair[1].virt_addr / Size4KiB::SIZE
+    })
+}
+
+/// Copies the file image of a segment straight from disk into its mapped
+/// pages. The rest of the memory image was zeroed when it was mapped.
+pub fn load_program_header<'a, T: StorageFormat<'a>>(
+    fs: &FileSystem<'a, T>,
+    file: &File,
     program_header: &ELF64ProgramHeader,
-    content: Vec<u8>,
-) {
+) -> Result<(), ElfError> {
     let file_offset = program_header.offset as usize;
     let file_size = program_header.file_image_size as usize;
-    let file_bytes = &content[file_offset..file_offset + file_size];
 
     let mem_ptr = program_header.virt_addr as *mut u8;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(mem_ptr, file_size) };
+    read_file(fs, file, buffer, file_offset)
+}
 
-    unsafe {
-        core::ptr::copy_nonoverlapping(file_bytes.as_ptr(), mem_ptr, file_size);
-        let mem_size = program_header.memory_size as usize;
-        if mem_size > file_size {
-            core::ptr::write_bytes(
-                mem_ptr.add(file_size),
-                0,
-                mem_size - file_size,
-            );
+/// Where the program interpreter of a dynamically linked executable is
+/// loaded.
+pub const INTERP_LOAD_BASE: u64 = 0x7f00_0000_0000;
+/// Longest `PT_INTERP` path the loader accepts.
+const MAX_INTERP_LEN: u64 = 256;
+
+/// An ELF file mapped into memory.
+#[derive(Debug)]
+pub struct LoadedImage {
+    pub entry: u64,
+    pub load_bias: u64,
+    pub program_headers_addr: u64,
+    pub program_header_size: u16,
+    pub program_header_entries: u16,
+    /// Path from the `PT_INTERP` segment, if the image is dynamically linked.
+    pub interpreter: Option<String>,
+    /// The VMAs of the image. Images the kernel relocates are mapped up
+    /// front, all others are paged in from the file on demand.
+    pub regions: Vec<MemoryRegion>,
+    /// Initialization image for thread-local storage, from `PT_TLS`.
+    pub tls: Option<TlsTemplate>,
+}
+
+/// Sets up the segments of `file`. Position-independent images are moved
+/// near `base`; they are relocated here unless they name an interpreter,
+/// which then does the relocation itself. Only images relocated here, or
+/// whose segments share pages, are loaded right away; the rest is paged in
+/// on first access.
+pub fn load_image<'a, T: StorageFormat<'a>>(
+    fs: &'static FileSystem<'a, T>,
+    file: &File,
+    base: u64,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> anyhow::Result<LoadedImage> {
+    let (header, program_headers) = get_elf64(fs, file)?;
+
+    let interpreter =
+        match program_headers.iter().find(|p| p.segment_type == PT_INTERP) {
+            Some(interp) => Some(read_interpreter(fs, file, interp)?),
+            None => None,
+        };
+
+    let load_bias = header.load_bias(&program_headers, base)?;
+    let segments = load_segments(&program_headers, load_bias)?;
+    let tls = match program_headers.iter().find(|p| p.segment_type == PT_TLS) {
+        Some(tls) => Some(TlsTemplate::new(tls, load_bias, &segments)?),
+        None => None,
+    };
+    let dynamic = program_headers
+        .iter()
+        .find(|p| p.segment_type == PT_DYNAMIC)
+        .filter(|_| header.is_position_independent() && interpreter.is_none());
+
+    let regions = match dynamic {
+        Some(dynamic) => {
+            load_now(fs, file, &segments, frame_allocator, || {
+                relocate(dynamic, load_bias, &segments)
+            })?
         }
+        None => segment_regions(fs, file, &segments, frame_allocator)?,
+    };
+
+    let mut symbols = USER_SYMBOLS.lock();
+    if let Err(e) = load_symbols(fs, file, &header, load_bias, &mut symbols) {
+        serial_println!("No symbols for {}: {}", file.path, e);
+    }
+    drop(symbols);
+
+    Ok(LoadedImage {
+        entry: header.entry_point() + load_bias,
+        load_bias,
+        program_headers_addr: program_headers_addr(
+            &header,
+            &program_headers,
+            &segments,
+            load_bias,
+        ),
+        program_header_size: header.program_header_size,
+        program_header_entries: header.program_header_entries,
+        interpreter,
+        regions,
+        tls,
+    })
+}
+
+/// Where the program header table ends up in memory, taken from `PT_PHDR`
+/// or else from the `PT_LOAD` segment covering it. Zero if it isn't loaded.
+fn program_headers_addr(
+    header: &ELF64Header,
+    program_headers: &[ELF64ProgramHeader],
+    segments: &[ELF64ProgramHeader],
+    load_bias: u64,
+) -> u64 {
+    if let Some(phdr) =
+        program_headers.iter().find(|p| p.segment_type == PT_PHDR)
+    {
+        return phdr.virt_addr + load_bias;
     }
+    let offset = header.program_header_entry;
+    segments
+        .iter()
+        .find(|s| s.offset <= offset && offset < s.offset + s.file_image_size)
+        .map_or(0, |s| s.virt_addr + (offset - s.offset))
+}
+
+fn read_interpreter<'a, T: StorageFormat<'a>>(
+    fs: &FileSystem<'a, T>,
+    file: &File,
+    interp: &ELF64ProgramHeader,
+) -> Result<String, ElfError> {
+    let size = interp.file_image_size;
+    if size == 0 || size > MAX_INTERP_LEN {
+        return Err(ElfError::InvalidInterpreter);
+    }
+    let mut path = alloc::vec![0u8; size as usize];
+    read_file(fs, file, &mut path, interp.offset as usize)?;
+    if let Some(nul) = path.iter().position(|&b| b == 0) {
+        path.truncate(nul);
+    }
+    String::from_utf8(path).map_err(|_| ElfError::InvalidInterpreter)
+}
+
+/// Finds the interpreter named by `path`, first by its file name next to
+/// `program`, then at `path` itself.
+fn find_interpreter<'a, T: StorageFormat<'a>>(
+    fs: &FileSystem<'a, T>,
+    program: &File,
+    path: &str,
+) -> anyhow::Result<File> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    fs.open(&format!("{}/{}", program.directory(), name))
+        .or_else(|_| fs.open(path))
+        .map_err(|_| anyhow!("Interpreter {} not found", path))
 }
 
 pub struct ELF64;
 
 impl Executor for ELF64 {
     fn load_executable<'a, T: StorageFormat<'a>>(
-        fs: &FileSystem<'a, T>,
+        fs: &'static FileSystem<'a, T>,
         file: &File,
-        mapper: &mut OffsetPageTable,
+        args: &[&str],
         frame_allocator: &mut BootInfoFrameAllocator,
     ) -> anyhow::Result<()> {
-        let (header, program_headers) = get_elf64(fs, file)?;
+        USER_SYMBOLS.lock().clear();
+        let mut process = Process::new(&file.path);
+        let program = load_image(fs, file, PIE_LOAD_BASE, frame_allocator)?;
+        for region in &program.regions {
+            process.add_vma(region.clone());
+        }
 
-        for program_header in program_headers {
-            map_program_header(&program_header, mapper, frame_allocator);
-            load_program_header(&program_header, fs.get_content(file));
+        let mut rip = program.entry;
+        let mut interp_base = 0;
+        if let Some(path) = &program.interpreter {
+            let interp_file = find_interpreter(fs, file, path)?;
+            let interp = load_image(
+                fs,
+                &interp_file,
+                INTERP_LOAD_BASE,
+                frame_allocator,
+            )?;
+            if interp.interpreter.is_some() {
+                return Err(ElfError::InvalidInterpreter.into());
+            }
+            rip = interp.entry;
+            interp_base = interp.load_bias;
+            for region in interp.regions {
+                process.add_vma(region);
+            }
         }
-        let (stack_top, ..) = map_stack(mapper, frame_allocator)?;
-        let rip = header.instruction_pointer_entry;
 
-        let user_context = UserContext::new(rip, stack_top.as_u64());
+        let (stack_top, stack_size, stack_bottom) =
+            with_mapper(|mapper| map_stack(mapper, frame_allocator))?;
+        process.add_vma(stack_region(stack_bottom));
+
+        // A dynamic linker sets up TLS for everything it loads itself.
+        if let (Some(tls), None) = (&program.tls, &program.interpreter) {
+            let image_end = tls
+                .addr
+                .checked_add(tls.file_size)
+                .ok_or(ElfError::InvalidTls)?;
+            populate(&mut process, tls.addr, image_end)?;
+            let base = VirtAddr::new(USER_TLS_BASE);
+            let thread_pointer = with_mapper(|mapper| {
+                allocate_tls(tls, base, mapper, frame_allocator)
+            })?;
+            process.add_region(
+                base.as_u64(),
+                base.as_u64() + tls.mapped_size(),
+                TLS_FLAGS,
+            );
+            process.fs_base = thread_pointer.as_u64();
+        }
+        let auxv = [
+            (AT_PHDR, program.program_headers_addr),
+            (AT_PHENT, program.program_header_size as u64),
+            (AT_PHNUM, program.program_header_entries as u64),
+            (AT_PAGESZ, 4096),
+            (AT_BASE, interp_base),
+            (AT_ENTRY, program.entry),
+        ];
+        let stack = unsafe {
+            core::slice::from_raw_parts_mut(
+                stack_bottom.as_mut_ptr::<u8>(),
+                stack_size as usize,
+            )
+        };
+        let mut argv = alloc::vec![file.path.as_str()];
+        argv.extend_from_slice(args);
+        let rsp =
+            build_initial_stack(stack, stack_top.as_u64(), &argv, &auxv, 8)?;
+
+        let user_context = UserContext::new(rip, rsp);
         serial_println!("{:#x?}", user_context);
+        set_fs_base(VirtAddr::new(process.fs_base));
+        *CURRENT_PROCESS.lock() = Some(process);
         unsafe {
             enter_user_mode(&user_context);
         };
@@ -293,3 +1115,133 @@ pub fn test_user_stack_setup(
     // Additional checks as needed, e.g., overlap
     Ok(())
 }
+
+#[cfg(test)]
+fn segment(
+    segment_type: u32,
+    flags: ELF64SegmentFlags,
+    virt_addr: u64,
+    offset: u64,
+    memory_size: u64,
+) -> ELF64ProgramHeader {
+    ELF64ProgramHeader {
+        segment_type,
+        segment_flags: flags.bits(),
+        offset,
+        virt_addr,
+        phys_addr: virt_addr,
+        file_image_size: memory_size,
+        memory_size,
+        alignment: 0x1000,
+    }
+}
+
+#[test_case]
+fn load_segments_skips_non_load_types() {
+    let rx = ELF64SegmentFlags::READABLE | ELF64SegmentFlags::EXACUTABLE;
+    let headers = alloc::vec![
+        segment(PT_PHDR, ELF64SegmentFlags::READABLE, 0x40_0040, 0x40, 0x38),
+        segment(PT_LOAD, rx, 0x40_1000, 0x1000, 0x100),
+        segment(PT_NOTE, ELF64SegmentFlags::READABLE, 0x40_0200, 0x200, 0x20),
+        segment(PT_GNU_STACK, ELF64SegmentFlags::WRITABLE, 0, 0, 0),
+    ];
+    let segments = load_segments(&headers, 0).unwrap();
+    assert_eq!(segments.len(), 1);
+    assert_eq!({ segments[0].segment_type }, PT_LOAD);
+}
+
+#[test_case]
+fn segment_pages_merge_shared_page() {
+    let r = ELF64SegmentFlags::READABLE;
+    let rx = ELF64SegmentFlags::READABLE | ELF64SegmentFlags::EXACUTABLE;
+    let rw = ELF64SegmentFlags::READABLE | ELF64SegmentFlags::WRITABLE;
+    let headers = alloc::vec![
+        segment(PT_LOAD, rw, 0x40_1800, 0x1800, 0x1000),
+        segment(PT_LOAD, r, 0x40_1000, 0x1000, 0x800),
+    ];
+    let segments = load_segments(&headers, 0).unwrap();
+    let pages = segment_pages(&segments).unwrap();
+
+    let page = |addr| Page::containing_address(VirtAddr::new(addr));
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let data = user | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    assert_eq!(pages.len(), 2);
+    assert_eq!(pages[0], (page(0x40_1000), data));
+    assert_eq!(pages[1], (page(0x40_2000), data));
+
+    let headers = alloc::vec![
+        segment(PT_LOAD, rw, 0x40_1800, 0x1800, 0x1000),
+        segment(PT_LOAD, rx, 0x40_1000, 0x1000, 0x800),
+    ];
+    let segments = load_segments(&headers, 0).unwrap();
+    assert_eq!(
+        segment_pages(&segments).unwrap_err(),
+        ElfError::WritableExecutablePage(0x40_1000)
+    );
+}
+
+#[test_case]
+fn segment_pages_cover_unaligned_segment() {
+    let headers = alloc::vec![segment(
+        PT_LOAD,
+        ELF64SegmentFlags::READABLE,
+        0x40_0ff0,
+        0xff0,
+        0x20,
+    )];
+    let segments = load_segments(&headers, 0).unwrap();
+    let pages = segment_pages(&segments).unwrap();
+    assert_eq!(pages.len(), 2);
+    assert_eq!(pages[0].0.start_address().as_u64(), 0x40_0000);
+    assert_eq!(pages[1].0.start_address().as_u64(), 0x40_1000);
+}
+
+#[test_case]
+fn load_segments_rejects_misaligned_offset() {
+    let headers = alloc::vec![segment(
+        PT_LOAD,
+        ELF64SegmentFlags::READABLE,
+        0x40_0010,
+        0x20,
+        0x10,
+    )];
+    assert_eq!(
+        load_segments(&headers, 0).unwrap_err(),
+        ElfError::MisalignedSegment {
+            virt_addr: 0x40_0010,
+            offset: 0x20,
+            alignment: 0x1000,
+        }
+    );
+}
+
+#[test_case]
+fn load_segments_rejects_overlap() {
+    let r = ELF64SegmentFlags::READABLE;
+    let headers = alloc::vec![
+        segment(PT_LOAD, r, 0x40_0000, 0, 0x1800),
+        segment(PT_LOAD, r, 0x40_1000, 0x1000, 0x100),
+    ];
+    assert_eq!(
+        load_segments(&headers, 0).unwrap_err(),
+        ElfError::OverlappingSegments(0x40_0000, 0x40_1000)
+    );
+}
+
+#[test_case]
+fn load_segments_rejects_kernel_ranges() {
+    let r = ELF64SegmentFlags::READABLE;
+    let heap = crate::memory::HEAP_START as u64;
+    let headers = alloc::vec![segment(PT_LOAD, r, heap, 0, 0x1000)];
+    assert_eq!(
+        load_segments(&headers, 0).unwrap_err(),
+        ElfError::SegmentOutOfBounds {
+            offset: 0,
+            file_image_size: 0x1000,
+            memory_size: 0x1000,
+        }
+    );
+    let kernel = crate::memory::KERNEL_START;
+    let headers = alloc::vec![segment(PT_LOAD, r, kernel, 0, 0x1000)];
+    assert!(load_segments(&headers, 0).is_err());
+}
@@ -0,0 +1,79 @@
+use crate::{
+    file_system::{File, FileSystem, StorageFormat},
+    memory::{allocator::BootInfoFrameAllocator, with_mapper},
+    serial_println,
+};
+
+use super::{
+    auxv::build_initial_stack,
+    elf64::{
+        load_segments, map_stack, segment_regions, stack_region,
+        ELF64ProgramHeader, ELF64SegmentFlags, PT_LOAD,
+    },
+    enter_user_mode,
+    process::{Process, CURRENT_PROCESS},
+    Executor, UserContext,
+};
+
+/// Address raw binaries are loaded at. Execution starts at the first byte.
+pub const FLAT_LOAD_ADDRESS: u64 = 0x40_0000;
+
+/// Raw 64-bit machine code without any header, like `hello.bin`. The
+/// whole file is mapped readable and executable; with nothing telling code
+/// and data apart, programs keep their writable data on the stack.
+pub struct FlatBinary;
+
+impl FlatBinary {
+    /// A single segment covering the whole file.
+    fn segment(file: &File) -> ELF64ProgramHeader {
+        let flags = ELF64SegmentFlags::READABLE | ELF64SegmentFlags::EXACUTABLE;
+        ELF64ProgramHeader {
+            segment_type: PT_LOAD,
+            segment_flags: flags.bits(),
+            offset: 0,
+            virt_addr: FLAT_LOAD_ADDRESS,
+            phys_addr: FLAT_LOAD_ADDRESS,
+            file_image_size: file.size as u64,
+            memory_size: file.size as u64,
+            alignment: 0x1000,
+        }
+    }
+}
+
+impl Executor for FlatBinary {
+    fn load_executable<'a, T: StorageFormat<'a>>(
+        fs: &'static FileSystem<'a, T>,
+        file: &File,
+        args: &[&str],
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> anyhow::Result<()> {
+        if file.size == 0 {
+            return Err(anyhow::anyhow!("{} is empty", file.path));
+        }
+        let segments = load_segments(&[Self::segment(file)], 0)?;
+        let regions = segment_regions(fs, file, &segments, frame_allocator)?;
+
+        let (stack_top, stack_size, stack_bottom) =
+            with_mapper(|mapper| map_stack(mapper, frame_allocator))?;
+        let mut process = Process::new(&file.path);
+        for region in regions {
+            process.add_vma(region);
+        }
+        process.add_vma(stack_region(stack_bottom));
+        let stack = unsafe {
+            core::slice::from_raw_parts_mut(
+                stack_bottom.as_mut_ptr::<u8>(),
+                stack_size as usize,
+            )
+        };
+        let mut argv = alloc::vec![file.path.as_str()];
+        argv.extend_from_slice(args);
+        let rsp =
+            build_initial_stack(stack, stack_top.as_u64(), &argv, &[], 8)?;
+
+        let user_context = UserContext::new(FLAT_LOAD_ADDRESS, rsp);
+        serial_println!("{:#x?}", user_context);
+        *CURRENT_PROCESS.lock() = Some(process);
+        unsafe { enter_user_mode(&user_context) }
+    }
+}
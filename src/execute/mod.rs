@@ -1,17 +1,31 @@
-use x86_64::structures::paging::OffsetPageTable;
-
 use crate::{
     file_system::{File, FileSystem, StorageFormat},
+    gdt::GDT,
     memory::allocator::BootInfoFrameAllocator,
 };
 
+pub mod auxv;
+pub mod core_dump;
+pub mod dynamic;
+pub mod elf32;
 pub mod elf64;
+pub mod flat;
+pub mod paging;
+pub mod process;
+pub mod registry;
+pub mod script;
+pub mod swap;
+pub mod symbols;
+pub mod tls;
 
 pub trait Executor {
+    /// Loads `file` and enters it in user mode. `args` are passed to the
+    /// program after its own path, which is always `argv[0]`. The process
+    /// may page its memory in from `fs` as long as it runs.
     fn load_executable<'a, T: StorageFormat<'a>>(
-        fs: &FileSystem<'a, T>,
+        fs: &'static FileSystem<'a, T>,
         file: &File,
-        mapper: &mut OffsetPageTable,
+        args: &[&str],
         frame_allocator: &mut BootInfoFrameAllocator,
     ) -> anyhow::Result<()>;
 }
@@ -27,9 +41,22 @@ pub struct UserContext {
 
 impl UserContext {
     pub fn new(entry_point: u64, user_stack_top: u64) -> Self {
-        // Standard GDT selectors for user mode
-        let cs = 0x23; //GDT.1.user_code_selector.0 as u64; // User code segment, RPL=3
-        let ss = 0x1b; //GDT.1.user_data_selector.0 as u64; // User data segment, RPL=3
+        let cs = GDT.1.user_code_selector.0 as u64; // User code segment, RPL=3
+        Self::with_code_segment(entry_point, user_stack_top, cs)
+    }
+
+    /// Context for a 32-bit program running in compatibility mode.
+    pub fn compat(entry_point: u32, user_stack_top: u32) -> Self {
+        let cs = GDT.1.user_code32_selector.0 as u64; // 32-bit code, RPL=3
+        Self::with_code_segment(entry_point as u64, user_stack_top as u64, cs)
+    }
+
+    fn with_code_segment(
+        entry_point: u64,
+        user_stack_top: u64,
+        cs: u64,
+    ) -> Self {
+        let ss = GDT.1.user_data_selector.0 as u64; // User data segment, RPL=3
         let rflags = 0x202; // Interrupts enabled
 
         Self {
@@ -42,20 +69,26 @@ impl UserContext {
     }
 }
 
+/// Drops to ring 3 at `ctx.rip` with the stack at `ctx.rsp`. DS and ES get
+/// the user data selector too, a null one faults in compatibility mode.
+///
+/// # Safety
+/// The code and stack in `ctx` must be mapped user accessible.
 pub unsafe fn enter_user_mode(ctx: &UserContext) -> ! {
     core::arch::asm!(
-        "mov rsp, {0}",
-        "push {0}",        // SS
-        "push {1}",        // RSP
-        "push {2}",        // RFLAGS
-        "push {3}",        // CS
-        "push {4}",        // RIP
+        "mov ds, {ss:x}",
+        "mov es, {ss:x}",
+        "push {ss}",        // SS
+        "push {rsp}",       // RSP
+        "push {rflags}",    // RFLAGS
+        "push {cs}",        // CS
+        "push {rip}",       // RIP
         "iretq",
-        in(reg) ctx.rsp,
-        in(reg) ctx.ss,
-        in(reg) ctx.rflags,
-        in(reg) ctx.cs,
-        in(reg) ctx.rip,
+        ss = in(reg) ctx.ss,
+        rsp = in(reg) ctx.rsp,
+        rflags = in(reg) ctx.rflags,
+        cs = in(reg) ctx.cs,
+        rip = in(reg) ctx.rip,
         options(noreturn)
     );
 }
@@ -0,0 +1,421 @@
+use alloc::{format, string::String, sync::Arc};
+use anyhow::anyhow;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MappedFrame, TranslateResult},
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+            PageSize, PageTableFlags, PhysFrame, Size4KiB, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+use crate::{
+    file_system::{page_cache::cached_page, File, FileSource},
+    gdt::guarded_kernel_stack,
+    memory::{
+        allocator::BootInfoFrameAllocator, mapper_locked, phys_to_virt,
+        with_mapper,
+    },
+};
+
+use super::{
+    elf64::USER_SPACE_END,
+    process::{Backing, MemoryRegion, Process, CURRENT_PROCESS},
+    swap::{reclaim, swap_in},
+};
+
+/// Where `mmap_file` starts looking for room.
+pub const MMAP_BASE: u64 = 0x7d00_0000_0000;
+
+/// Marks a page whose frame is shared read-only and copied on the first
+/// write to it.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Serves a page fault at `addr` from the VMAs of the current process:
+/// the page is allocated, filled from its backing and mapped. An error
+/// means the access was invalid and the fault has to be reported.
+pub fn handle_page_fault(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> anyhow::Result<()> {
+    if mapper_locked() {
+        return Err(anyhow!("Fault while the page tables are being changed"));
+    }
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            return copy_on_write(addr);
+        }
+        return Err(anyhow!("Protection violation"));
+    }
+    // The process may be locked by the code that faulted, e.g. while a
+    // core dump is written.
+    let mut process = CURRENT_PROCESS
+        .try_lock()
+        .ok_or(anyhow!("The current process is locked"))?;
+    let process = process.as_mut().ok_or(anyhow!("No process is running"))?;
+    let index = process
+        .fault_region(addr.as_u64())
+        .ok_or(anyhow!("No memory region at {:?}", addr))?;
+    check_access(&process.regions[index], error_code)?;
+    process.grow_to(index, addr.as_u64());
+    fault_in(process, Page::containing_address(addr))
+}
+
+/// Maps `len` bytes of `file` on `fs` from `offset` into `process` at the
+/// first free address from [`MMAP_BASE`] on and returns that address. Pages
+/// come from the page cache when first touched; a writable mapping is
+/// private, its pages are copied on the first write. Memory past the end of
+/// the file reads as zero.
+pub fn mmap_file(
+    process: &mut Process,
+    fs: &'static dyn FileSource,
+    file: &File,
+    offset: u64,
+    len: u64,
+    writable: bool,
+) -> anyhow::Result<u64> {
+    if len == 0 || !offset.is_multiple_of(Size4KiB::SIZE) {
+        return Err(anyhow!("Invalid mapping of {} bytes at {}", len, offset));
+    }
+    let size = len.next_multiple_of(Size4KiB::SIZE);
+    let start = process.find_free(MMAP_BASE, size);
+    if start + size > USER_SPACE_END {
+        return Err(anyhow!("No room to map {}", file.path));
+    }
+    let mut flags = PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    let backing = Backing::File {
+        fs,
+        file: Arc::new(file.clone()),
+        offset,
+        start,
+        size: (file.size as u64).saturating_sub(offset).min(len),
+    };
+    process.add_vma(MemoryRegion::new(start, start + size, flags, backing));
+    Ok(start)
+}
+
+/// The task whose stack overflowed if `addr` is in the guard page of a
+/// kernel stack or of a stack of the current process.
+pub fn stack_overflow(addr: VirtAddr) -> Option<String> {
+    if let Some(stack) = guarded_kernel_stack(addr) {
+        return Some(format!("kernel ({} stack)", stack));
+    }
+    let process = CURRENT_PROCESS.try_lock()?;
+    let process = process.as_ref()?;
+    process
+        .regions
+        .iter()
+        .find(|r| r.contains(addr.as_u64()))
+        .filter(|r| matches!(r.backing, Backing::Guard))
+        .map(|_| format!("{} ({})", process.pid, process.path))
+}
+
+/// Maps every page of `[start, end)` that isn't present yet, for the kernel
+/// to access memory of `process` before it runs.
+pub fn populate(
+    process: &mut Process,
+    start: u64,
+    end: u64,
+) -> anyhow::Result<()> {
+    if start >= end {
+        return Ok(());
+    }
+    let first = Page::containing_address(VirtAddr::new(start));
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first, last) {
+        let addr = page.start_address();
+        if with_mapper(|mapper| mapper.translate_addr(addr)).is_none() {
+            fault_in(process, page)?;
+        }
+    }
+    Ok(())
+}
+
+/// Makes `page` of `process` present, reading it back from swap if it was
+/// evicted or else filling it from its region's backing.
+fn fault_in(process: &mut Process, page: Page<Size4KiB>) -> anyhow::Result<()> {
+    reclaim(process);
+    let addr = page.start_address().as_u64();
+    let region = process
+        .regions
+        .iter()
+        .find(|r| r.contains(addr))
+        .ok_or(anyhow!("No memory region at {:#x}", addr))?;
+    if let Backing::Guard = region.backing {
+        return Err(anyhow!("{:#x} is a guard page", addr));
+    }
+    match process.swapped.get(&addr) {
+        Some(&slot) => {
+            swap_in(slot, page, region.flags)?;
+            process.swapped.remove(&addr);
+            Ok(())
+        }
+        None => map_page(region, page),
+    }
+}
+
+/// Maps `to` to the frame behind `from`. A writable page becomes
+/// copy-on-write in both places.
+pub fn share_page(from: VirtAddr, to: VirtAddr) -> anyhow::Result<()> {
+    let (frame, flags) = with_mapper(|mapper| translate_4kib(mapper, from))?;
+    let flags = if flags.contains(PageTableFlags::WRITABLE) {
+        (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+    } else {
+        flags
+    };
+    let from = Page::<Size4KiB>::containing_address(from);
+    let to = Page::<Size4KiB>::containing_address(to);
+    let mut frame_allocator = BootInfoFrameAllocator::shared();
+    // Counting the reference may allocate, so not with the mapper held.
+    frame_allocator.share_frame(frame);
+    with_mapper(|mapper| unsafe {
+        let shared = mapper
+            .map_to(to, frame, flags, &mut frame_allocator)
+            .map(|flush| flush.flush())
+            .map_err(|e| anyhow!("Failed to map {:?}: {:?}", to, e));
+        if shared.is_err() {
+            frame_allocator.deallocate_frame(frame);
+            return shared;
+        }
+        mapper
+            .update_flags(from, flags)
+            .map_err(|e| anyhow!("Failed to protect {:?}: {:?}", from, e))?
+            .flush();
+        Ok(())
+    })
+}
+
+/// Duplicates the VMA of `process` starting at `start` at `to`. Its
+/// present pages are shared copy-on-write, the others are filled from the
+/// backing when first touched, like in the original.
+pub fn duplicate_region(
+    process: &mut Process,
+    start: u64,
+    to: u64,
+) -> anyhow::Result<()> {
+    let region = process
+        .regions
+        .iter()
+        .find(|r| r.start == start)
+        .cloned()
+        .ok_or(anyhow!("No memory region at {:#x}", start))?;
+    let end = to.checked_add(region.size()).ok_or(anyhow!(
+        "Region at {:#x} does not fit at {:#x}",
+        start,
+        to
+    ))?;
+    if !to.is_multiple_of(Size4KiB::SIZE)
+        || process.regions.iter().any(|r| r.start < end && to < r.end)
+    {
+        return Err(anyhow!("Cannot place region at {:#x}", to));
+    }
+
+    for offset in (0..region.size()).step_by(Size4KiB::SIZE as usize) {
+        let from = VirtAddr::new(region.start + offset);
+        // Evicted pages have to be shared as well.
+        if process.swapped.contains_key(&from.as_u64()) {
+            fault_in(process, Page::containing_address(from))?;
+        }
+        if with_mapper(|mapper| mapper.translate_addr(from)).is_some() {
+            share_page(from, VirtAddr::new(to + offset))?;
+        }
+    }
+
+    let backing = match region.backing {
+        Backing::File {
+            fs,
+            file,
+            offset,
+            start: file_start,
+            size,
+        } => Backing::File {
+            fs,
+            file,
+            offset,
+            start: to + (file_start - region.start),
+            size,
+        },
+        // Only the original stack grows.
+        Backing::Anonymous | Backing::Stack { .. } => Backing::Anonymous,
+        Backing::Guard => Backing::Guard,
+    };
+    process.add_vma(MemoryRegion::new(to, end, region.flags, backing));
+    Ok(())
+}
+
+/// Gives the writer of a copy-on-write page its own copy of the frame, or
+/// the frame itself once no other page maps it.
+fn copy_on_write(addr: VirtAddr) -> anyhow::Result<()> {
+    with_mapper(|mapper| copy_on_write_in(mapper, addr))
+}
+
+fn copy_on_write_in(
+    mapper: &mut OffsetPageTable,
+    addr: VirtAddr,
+) -> anyhow::Result<()> {
+    let (frame, flags) = translate_4kib(mapper, addr)?;
+    if !flags.contains(COPY_ON_WRITE) {
+        return Err(anyhow!("Write to read-only memory"));
+    }
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let mut frame_allocator = BootInfoFrameAllocator::shared();
+    if frame_allocator.reference_count(frame) <= 1 {
+        unsafe {
+            mapper
+                .update_flags(page, flags)
+                .map_err(|e| {
+                    anyhow!("Failed to unprotect {:?}: {:?}", page, e)
+                })?
+                .flush();
+        }
+        return Ok(());
+    }
+
+    let copy = frame_allocator
+        .allocate_frame()
+        .ok_or(anyhow!("Out of physical memory"))?;
+    let (from, to) = phys_to_virt(frame.start_address())
+        .zip(phys_to_virt(copy.start_address()))
+        .ok_or(anyhow!("Paging is not set up"))?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            from.as_ptr::<u8>(),
+            to.as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        );
+        mapper
+            .unmap(page)
+            .map_err(|e| anyhow!("Failed to unmap {:?}: {:?}", page, e))?
+            .1
+            .flush();
+        mapper
+            .map_to(page, copy, flags, &mut frame_allocator)
+            .map_err(|e| anyhow!("Failed to map {:?}: {:?}", page, e))?
+            .flush();
+        frame_allocator.deallocate_frame(frame);
+    }
+    Ok(())
+}
+
+pub(super) fn translate_4kib(
+    mapper: &OffsetPageTable,
+    addr: VirtAddr,
+) -> anyhow::Result<(PhysFrame<Size4KiB>, PageTableFlags)> {
+    match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => Ok((frame, flags)),
+        TranslateResult::Mapped { .. } => {
+            Err(anyhow!("{:?} is in a huge page", addr))
+        }
+        _ => Err(anyhow!("{:?} is not mapped", addr)),
+    }
+}
+
+fn check_access(
+    region: &MemoryRegion,
+    error_code: PageFaultErrorCode,
+) -> anyhow::Result<()> {
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return Err(anyhow!("Write to read-only memory"));
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && region.flags.contains(PageTableFlags::NO_EXECUTE)
+    {
+        return Err(anyhow!("Instruction fetch from non-executable memory"));
+    }
+    if error_code.contains(PageFaultErrorCode::USER_MODE)
+        && !region.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    {
+        return Err(anyhow!("User access to kernel memory"));
+    }
+    Ok(())
+}
+
+/// Allocates a frame for `page`, fills it from the backing of `region`
+/// through the physical memory window and maps it with the region's flags.
+/// Whole file pages are shared through the page cache instead, and are
+/// copied on write in writable regions.
+fn map_page(region: &MemoryRegion, page: Page<Size4KiB>) -> anyhow::Result<()> {
+    let mut frame_allocator = BootInfoFrameAllocator::shared();
+    let mut flags = region.flags;
+    let frame = match cached_frame(region, page)? {
+        Some(frame) => {
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            }
+            frame
+        }
+        None => {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(anyhow!("Out of physical memory"))?;
+            if let Err(e) = fill_frame(region, page, frame) {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(e);
+            }
+            frame
+        }
+    };
+    let mapped = with_mapper(|mapper| unsafe {
+        mapper
+            .map_to(page, frame, flags, &mut frame_allocator)
+            .map(|flush| flush.flush())
+            .map_err(|e| anyhow!("Failed to map {:?}: {:?}", page, e))
+    });
+    if mapped.is_err() {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    mapped
+}
+
+/// A reference to the page cache frame for `page`, if `region` maps a
+/// whole page of its file there.
+fn cached_frame(
+    region: &MemoryRegion,
+    page: Page<Size4KiB>,
+) -> anyhow::Result<Option<PhysFrame<Size4KiB>>> {
+    let (Backing::File { fs, file, .. }, Some(index)) = (
+        &region.backing,
+        region.file_page(page.start_address().as_u64()),
+    ) else {
+        return Ok(None);
+    };
+    cached_page(*fs, file, index).map(Some)
+}
+
+fn fill_frame(
+    region: &MemoryRegion,
+    page: Page<Size4KiB>,
+    frame: PhysFrame<Size4KiB>,
+) -> anyhow::Result<()> {
+    let virt = phys_to_virt(frame.start_address())
+        .ok_or(anyhow!("Paging is not set up"))?;
+    let memory = unsafe {
+        core::slice::from_raw_parts_mut(
+            virt.as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        )
+    };
+    memory.fill(0);
+    let page_start = page.start_address().as_u64();
+    if let (Backing::File { fs, file, .. }, Some((offset, at, len))) =
+        (&region.backing, region.file_bytes(page_start))
+    {
+        fs.read_bytes(file, &mut memory[at..at + len], offset as usize)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,384 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+
+use crate::file_system::{File, FileSource};
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    /// The user program currently running, if any.
+    pub static ref CURRENT_PROCESS: Mutex<Option<Process>> = Mutex::new(None);
+}
+
+/// Where the contents of a [`MemoryRegion`] come from when one of its pages
+/// is first touched.
+#[derive(Debug, Clone)]
+pub enum Backing {
+    /// Zero-filled memory.
+    Anonymous,
+    /// `size` bytes of `file` from `offset`, placed at `start`. The rest of
+    /// the region is zero-filled, like the `.bss` of a segment. The file is
+    /// read from `fs`, the file system it was opened on.
+    File {
+        fs: &'static dyn FileSource,
+        file: Arc<File>,
+        offset: u64,
+        start: u64,
+        size: u64,
+    },
+    /// Zero-filled memory that grows down on faults, as far as `limit`.
+    Stack { limit: u64 },
+    /// Never mapped, a fault here is a stack overflow.
+    Guard,
+}
+
+/// A virtual memory area: a range of user memory mapped with the same flags
+/// and backing. Its pages are not necessarily present yet.
+#[derive(Debug, Clone)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl MemoryRegion {
+    pub fn new(
+        start: u64,
+        end: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Self {
+        Self {
+            start,
+            end,
+            flags,
+            backing,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// The part of the page at `page_start` that comes from the backing
+    /// file, as its file offset, offset into the page and length.
+    pub fn file_bytes(&self, page_start: u64) -> Option<(u64, usize, usize)> {
+        let Backing::File {
+            offset,
+            start,
+            size,
+            ..
+        } = self.backing
+        else {
+            return None;
+        };
+        let from = page_start.max(start);
+        let to = (page_start + Size4KiB::SIZE).min(start + size);
+        (from < to).then(|| {
+            (
+                offset + (from - start),
+                (from - page_start) as usize,
+                (to - from) as usize,
+            )
+        })
+    }
+
+    /// The page of the backing file that the page at `page_start` shows
+    /// as a whole, so its frame can come from the page cache. Pages that
+    /// also hold zero-fill or start mid-page in the file are private.
+    pub fn file_page(&self, page_start: u64) -> Option<u64> {
+        let Backing::File { file, .. } = &self.backing else {
+            return None;
+        };
+        let (offset, at, len) = self.file_bytes(page_start)?;
+        let whole = len as u64 == Size4KiB::SIZE
+            || offset + len as u64 >= file.size as u64;
+        (at == 0 && offset.is_multiple_of(Size4KiB::SIZE) && whole)
+            .then_some(offset / Size4KiB::SIZE)
+    }
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pub pid: u64,
+    pub path: String,
+    /// Mapped user memory, in the order it was added.
+    pub regions: Vec<MemoryRegion>,
+    /// Thread pointer of the main thread, loaded into the FS base.
+    pub fs_base: u64,
+    /// Swap slot of every page that was evicted, by page address.
+    pub swapped: BTreeMap<u64, usize>,
+}
+
+impl Process {
+    pub fn new(path: &str) -> Self {
+        Self {
+            pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            path: path.into(),
+            regions: Vec::new(),
+            fs_base: 0,
+            swapped: BTreeMap::new(),
+        }
+    }
+
+    /// Adds anonymous memory at `[start, end)`, extending the last region
+    /// if it continues it with the same flags.
+    pub fn add_region(&mut self, start: u64, end: u64, flags: PageTableFlags) {
+        push_anonymous(&mut self.regions, start, end, flags);
+    }
+
+    /// Adds `region`. A stack also gets a guard page below its limit.
+    pub fn add_vma(&mut self, region: MemoryRegion) {
+        if let Backing::Stack { limit } = region.backing {
+            self.regions.push(MemoryRegion::new(
+                limit - Size4KiB::SIZE,
+                limit,
+                PageTableFlags::empty(),
+                Backing::Guard,
+            ));
+        }
+        self.regions.push(region);
+    }
+
+    /// The lowest page-aligned address from `from` on where `size` bytes
+    /// fit without overlapping a region.
+    pub fn find_free(&self, from: u64, size: u64) -> u64 {
+        let mut start = from.next_multiple_of(Size4KiB::SIZE);
+        while let Some(region) = self
+            .regions
+            .iter()
+            .find(|r| r.start < start + size && start < r.end)
+        {
+            start = region.end.next_multiple_of(Size4KiB::SIZE);
+        }
+        start
+    }
+
+    /// Index of the region a fault at `addr` should be served from. A fault
+    /// just below a stack is served from the stack, unless growing it down
+    /// to the page of `addr` would pass its limit or run into another
+    /// region. The stack only grows with `grow_to`, once the access is
+    /// known to be valid.
+    pub fn fault_region(&self, addr: u64) -> Option<usize> {
+        if let Some(index) = self.regions.iter().position(|r| r.contains(addr))
+        {
+            return Some(index);
+        }
+        let page_start = addr & !(Size4KiB::SIZE - 1);
+        let index = self.regions.iter().position(|r| {
+            matches!(r.backing, Backing::Stack { limit }
+                if limit <= page_start && addr < r.start)
+        })?;
+        let stack_start = self.regions[index].start;
+        if self
+            .regions
+            .iter()
+            .any(|r| r.start < stack_start && r.end > page_start)
+        {
+            return None;
+        }
+        Some(index)
+    }
+
+    /// Whether all of `[start, start + len)` lies in user-accessible
+    /// regions, writable ones if `writable` is set. For checking pointers
+    /// passed in by the program before the kernel touches them.
+    pub fn is_user_range(&self, start: u64, len: u64, writable: bool) -> bool {
+        let Some(end) = start.checked_add(len) else {
+            return false;
+        };
+        let mut addr = start;
+        while addr < end {
+            let Some(region) = self.regions.iter().find(|r| r.contains(addr))
+            else {
+                return false;
+            };
+            if !region.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                || writable && !region.flags.contains(PageTableFlags::WRITABLE)
+            {
+                return false;
+            }
+            addr = region.end;
+        }
+        true
+    }
+
+    /// The growing stack of the main thread, as far as it is mapped now.
+    pub fn stack(&self) -> Option<&MemoryRegion> {
+        self.regions
+            .iter()
+            .find(|r| matches!(r.backing, Backing::Stack { .. }))
+    }
+
+    /// Extends the region at `index` down to the page of `addr`, for a
+    /// fault below a stack found by `fault_region`.
+    pub fn grow_to(&mut self, index: usize, addr: u64) {
+        let region = &mut self.regions[index];
+        region.start = region.start.min(addr & !(Size4KiB::SIZE - 1));
+    }
+}
+
+/// Anonymous regions for pages that are already mapped, as returned by
+/// `segment_pages`.
+pub fn page_regions(pages: &[(Page, PageTableFlags)]) -> Vec<MemoryRegion> {
+    let mut regions = Vec::new();
+    for (page, flags) in pages {
+        let start = page.start_address().as_u64();
+        push_anonymous(&mut regions, start, start + page.size(), *flags);
+    }
+    regions
+}
+
+/// Adds anonymous memory to `regions`, extending the last one if it
+/// continues it with the same flags.
+fn push_anonymous(
+    regions: &mut Vec<MemoryRegion>,
+    start: u64,
+    end: u64,
+    flags: PageTableFlags,
+) {
+    if let Some(last) = regions.last_mut() {
+        if last.end == start
+            && last.flags == flags
+            && matches!(last.backing, Backing::Anonymous)
+        {
+            last.end = end;
+            return;
+        }
+    }
+    regions.push(MemoryRegion::new(start, end, flags, Backing::Anonymous));
+}
+
+#[test_case]
+fn process_merges_adjacent_regions() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let writable = flags | PageTableFlags::WRITABLE;
+    let mut process = Process::new("/test.elf");
+    process.add_region(0x40_0000, 0x40_1000, flags);
+    process.add_region(0x40_1000, 0x40_2000, flags);
+    process.add_region(0x40_2000, 0x40_3000, writable);
+    process.add_region(0x50_0000, 0x50_1000, writable);
+    assert_eq!(process.regions.len(), 3);
+    assert_eq!(process.regions[0].size(), 0x2000);
+}
+
+#[test_case]
+fn user_ranges_must_be_mapped() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let writable = flags | PageTableFlags::WRITABLE;
+    let mut process = Process::new("/test.elf");
+    process.add_region(0x40_0000, 0x40_1000, flags);
+    process.add_region(0x40_1000, 0x40_2000, writable);
+    assert!(process.is_user_range(0x40_0ffc, 8, false));
+    assert!(!process.is_user_range(0x40_0ffc, 8, true));
+    assert!(process.is_user_range(0x40_1ff8, 8, true));
+    assert!(!process.is_user_range(0x40_1ffc, 8, true));
+    assert!(!process.is_user_range(u64::MAX - 4, 8, false));
+}
+
+#[test_case]
+fn stack_grows_down_to_its_limit() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut process = Process::new("/test.elf");
+    process.add_region(0x7000_0000, 0x7000_1000, flags);
+    process.add_vma(MemoryRegion::new(
+        0x7fff_c000,
+        0x8000_0000,
+        flags,
+        Backing::Stack { limit: 0x7ff0_0000 },
+    ));
+    let stack = process.fault_region(0x7fff_a008).unwrap();
+    assert_eq!(process.regions[stack].start, 0x7fff_c000);
+    process.grow_to(stack, 0x7fff_a008);
+    assert_eq!(process.regions[stack].start, 0x7fff_a000);
+    let guard = process.fault_region(0x7fef_ffff).unwrap();
+    assert!(matches!(process.regions[guard].backing, Backing::Guard));
+    assert!(process.fault_region(0x7fef_efff).is_none());
+    assert!(process.fault_region(0x6fff_ffff).is_none());
+}
+
+/// Regions in the tests never read their file.
+#[cfg(test)]
+struct NoFiles;
+
+#[cfg(test)]
+impl FileSource for NoFiles {
+    fn read_bytes(
+        &self,
+        file: &File,
+        _buffer: &mut [u8],
+        _offset: usize,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("{} is not on a file system", file.path))
+    }
+}
+
+#[test_case]
+fn file_bytes_cover_partial_pages() {
+    let file = Arc::new(File {
+        name: "TEST".into(),
+        ext: "ELF".into(),
+        path: "/TEST.ELF".into(),
+        start_sector: 0,
+        start_cluster: 0,
+        size: 0x3000,
+        time_stamp: Default::default(),
+    });
+    let region = MemoryRegion::new(
+        0x40_0000,
+        0x40_3000,
+        PageTableFlags::PRESENT,
+        Backing::File {
+            fs: &NoFiles,
+            file,
+            offset: 0x1100,
+            start: 0x40_0100,
+            size: 0x1000,
+        },
+    );
+    assert_eq!(region.file_bytes(0x40_0000), Some((0x1100, 0x100, 0xf00)));
+    assert_eq!(region.file_bytes(0x40_1000), Some((0x2000, 0, 0x100)));
+    assert_eq!(region.file_bytes(0x40_2000), None);
+    assert_eq!(region.file_page(0x40_0000), None);
+}
+
+#[test_case]
+fn file_pages_are_shared_when_aligned() {
+    let file = Arc::new(File {
+        name: "DATA".into(),
+        ext: "BIN".into(),
+        path: "/DATA.BIN".into(),
+        start_sector: 0,
+        start_cluster: 0,
+        size: 0x1800,
+        time_stamp: Default::default(),
+    });
+    let mut process = Process::new("/test.elf");
+    let start = process.find_free(0x10_0000, 0x2000);
+    assert_eq!(start, 0x10_0000);
+    process.add_vma(MemoryRegion::new(
+        start,
+        start + 0x2000,
+        PageTableFlags::PRESENT,
+        Backing::File {
+            fs: &NoFiles,
+            file,
+            offset: 0,
+            start,
+            size: 0x1800,
+        },
+    ));
+    assert_eq!(process.regions[0].file_page(start), Some(0));
+    // The last page is cut off by the end of the file.
+    assert_eq!(process.regions[0].file_page(start + 0x1000), Some(1));
+    assert_eq!(process.find_free(0x10_0000, 0x1000), 0x10_2000);
+}
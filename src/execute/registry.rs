@@ -0,0 +1,208 @@
+use alloc::vec::Vec;
+use anyhow::anyhow;
+
+use crate::{
+    file_system::{File, FileSystem, StorageFormat},
+    memory::allocator::BootInfoFrameAllocator,
+};
+
+use super::{
+    elf32::ELF32, elf64::ELF64, flat::FlatBinary, script::Script, Executor,
+};
+
+/// How many bytes from the start of a file are available to [`Matcher`]s.
+pub const MAGIC_LEN: usize = 64;
+
+/// Decides whether a [`BinaryFormat`] handles a file, like the rules of
+/// Linux `binfmt_misc`.
+#[derive(Debug, Clone, Copy)]
+pub enum Matcher {
+    /// The file has `magic` at byte `offset`.
+    Magic { offset: usize, magic: &'static [u8] },
+    /// The file name ends in `.<extension>`.
+    Extension(&'static str),
+}
+
+impl Matcher {
+    pub fn matches(&self, header: &[u8], file: &File) -> bool {
+        match *self {
+            Matcher::Magic { offset, magic } => header
+                .get(offset..offset + magic.len())
+                .is_some_and(|bytes| bytes == magic),
+            Matcher::Extension(extension) => {
+                file.ext.eq_ignore_ascii_case(extension)
+            }
+        }
+    }
+}
+
+/// Loads a file and enters it. The registry the format was found in is
+/// passed along for formats that hand off to another executable.
+pub type LoadFn<T> = fn(
+    &ExecutorRegistry<T>,
+    &'static FileSystem<'static, T>,
+    &File,
+    &[&str],
+    &mut BootInfoFrameAllocator,
+) -> anyhow::Result<()>;
+
+/// An executable format the registry can dispatch to.
+pub struct BinaryFormat<T: StorageFormat<'static> + 'static> {
+    pub name: &'static str,
+    pub matcher: Matcher,
+    pub load: LoadFn<T>,
+}
+
+impl<T: StorageFormat<'static> + 'static> BinaryFormat<T> {
+    pub fn new(name: &'static str, matcher: Matcher, load: LoadFn<T>) -> Self {
+        Self {
+            name,
+            matcher,
+            load,
+        }
+    }
+
+    /// Format backed by an [`Executor`] implementation.
+    pub fn of<E: Executor>(name: &'static str, matcher: Matcher) -> Self {
+        Self::new(name, matcher, |_, fs, file, args, frame_allocator| {
+            E::load_executable(fs, file, args, frame_allocator)
+        })
+    }
+}
+
+/// The executable formats known to the kernel. Formats registered later
+/// take precedence, so kernel modules can override the built-in ones.
+/// Programs run from file systems that live as long as the kernel, since
+/// their memory may be paged in from there while they run.
+pub struct ExecutorRegistry<T: StorageFormat<'static> + 'static> {
+    formats: Vec<BinaryFormat<T>>,
+}
+
+impl<T: StorageFormat<'static> + 'static> ExecutorRegistry<T> {
+    pub fn empty() -> Self {
+        Self {
+            formats: Vec::new(),
+        }
+    }
+
+    /// Registry with the ELF, script and flat binary formats.
+    pub fn new() -> Self {
+        let mut registry = Self::empty();
+        registry.register(BinaryFormat::of::<FlatBinary>(
+            "flat",
+            Matcher::Extension("bin"),
+        ));
+        registry.register(BinaryFormat::new(
+            "script",
+            Matcher::Magic {
+                offset: 0,
+                magic: b"#!",
+            },
+            Script::load_executable::<T>,
+        ));
+        registry.register(BinaryFormat::of::<ELF32>(
+            "elf32",
+            Matcher::Magic {
+                offset: 0,
+                magic: b"\x7fELF\x01",
+            },
+        ));
+        registry.register(BinaryFormat::of::<ELF64>(
+            "elf64",
+            Matcher::Magic {
+                offset: 0,
+                magic: b"\x7fELF\x02",
+            },
+        ));
+        registry
+    }
+
+    pub fn register(&mut self, format: BinaryFormat<T>) {
+        self.formats.push(format);
+    }
+
+    /// Removes the format called `name`, returning whether it was there.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        let len = self.formats.len();
+        self.formats.retain(|format| format.name != name);
+        self.formats.len() != len
+    }
+
+    /// The format that handles a file starting with `header`.
+    pub fn find(&self, header: &[u8], file: &File) -> Option<&BinaryFormat<T>> {
+        self.formats
+            .iter()
+            .rev()
+            .find(|format| format.matcher.matches(header, file))
+    }
+
+    /// Opens the file at `path` and runs it with the matching format.
+    pub fn exec(
+        &self,
+        fs: &'static FileSystem<'static, T>,
+        path: &str,
+        args: &[&str],
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> anyhow::Result<()> {
+        let file = fs.open(path)?;
+        self.exec_file(fs, &file, args, frame_allocator)
+    }
+
+    pub fn exec_file(
+        &self,
+        fs: &'static FileSystem<'static, T>,
+        file: &File,
+        args: &[&str],
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> anyhow::Result<()> {
+        let format = self.format_of(fs, file)?;
+        (format.load)(self, fs, file, args, frame_allocator)
+    }
+
+    /// Reads the start of `file` and finds the format handling it.
+    pub fn format_of(
+        &self,
+        fs: &FileSystem<'static, T>,
+        file: &File,
+    ) -> anyhow::Result<&BinaryFormat<T>> {
+        let mut header = [0u8; MAGIC_LEN];
+        let len = core::cmp::min(file.size as usize, MAGIC_LEN);
+        fs.read_bytes(file, &mut header[..len], 0)?;
+        self.find(&header[..len], file)
+            .ok_or(anyhow!("{} is not an executable format", file.path))
+    }
+}
+
+impl<T: StorageFormat<'static> + 'static> Default for ExecutorRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn registry_dispatches_on_magic_and_extension() {
+    use crate::file_system::{fat16::FAT16, TimeStamp};
+
+    let file = |name: &str, ext: &str| File {
+        name: name.into(),
+        ext: ext.into(),
+        path: alloc::format!("/{}.{}", name, ext),
+        start_sector: 0,
+        start_cluster: 0,
+        size: 0,
+        time_stamp: TimeStamp::default(),
+    };
+    let registry: ExecutorRegistry<FAT16> = ExecutorRegistry::new();
+    let name = |header: &[u8], file: &File| {
+        registry.find(header, file).map(|format| format.name)
+    };
+
+    let elf = file("printer", "elf");
+    assert_eq!(name(b"\x7fELF\x02\x01\x01", &elf), Some("elf64"));
+    assert_eq!(name(b"\x7fELF\x01\x01\x01", &elf), Some("elf32"));
+    assert_eq!(name(b"#!/bin/sh\n", &file("run", "sh")), Some("script"));
+    // The first instruction of `hello.bin`, `mov ebx, 1`.
+    let flat = b"\xbb\x01\x00\x00\x00";
+    assert_eq!(name(flat, &file("hello", "bin")), Some("flat"));
+    assert_eq!(name(b"Hello", &file("test1", "txt")), None);
+}
@@ -0,0 +1,101 @@
+use alloc::vec::Vec;
+use anyhow::anyhow;
+
+use crate::{
+    file_system::{File, FileSystem, StorageFormat},
+    memory::allocator::BootInfoFrameAllocator,
+};
+
+use super::registry::ExecutorRegistry;
+
+/// Longest `#!` line the script executor reads.
+pub const MAX_SHEBANG_LEN: usize = 128;
+
+/// Interpreter path and optional single argument of a `#!` line.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Shebang<'a> {
+    pub interpreter: &'a str,
+    pub argument: Option<&'a str>,
+}
+
+/// Parses the first line of a script. Like Linux, everything after the
+/// interpreter path is passed on as one argument.
+pub fn parse_shebang(bytes: &[u8]) -> Option<Shebang<'_>> {
+    let line = bytes.strip_prefix(b"#!")?;
+    let end = line.iter().position(|&b| b == b'\n')?;
+    let line = core::str::from_utf8(&line[..end]).ok()?.trim();
+
+    let (interpreter, argument) = match line.split_once([' ', '\t']) {
+        Some((interpreter, argument)) => (interpreter, Some(argument.trim())),
+        None => (line, None),
+    };
+    if interpreter.is_empty() {
+        return None;
+    }
+    Some(Shebang {
+        interpreter,
+        argument: argument.filter(|a| !a.is_empty()),
+    })
+}
+
+/// Scripts starting with `#!`, run by re-executing the named interpreter
+/// with the script path as an argument. The interpreter may use any format
+/// of the registry the script was found in, except another script.
+pub struct Script;
+
+impl Script {
+    pub fn load_executable<T: StorageFormat<'static> + 'static>(
+        registry: &ExecutorRegistry<T>,
+        fs: &'static FileSystem<'static, T>,
+        file: &File,
+        args: &[&str],
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> anyhow::Result<()> {
+        let mut line = [0u8; MAX_SHEBANG_LEN];
+        let len = core::cmp::min(file.size as usize, MAX_SHEBANG_LEN);
+        fs.read_bytes(file, &mut line[..len], 0)?;
+        let shebang = parse_shebang(&line[..len])
+            .ok_or(anyhow!("{} has no valid #! line", file.path))?;
+
+        let interpreter = fs.open(shebang.interpreter)?;
+        let mut interpreter_args: Vec<&str> = Vec::new();
+        interpreter_args.extend(shebang.argument);
+        interpreter_args.push(file.path.as_str());
+        interpreter_args.extend_from_slice(args);
+
+        let format = registry.format_of(fs, &interpreter)?;
+        if format.name == "script" {
+            return Err(anyhow!(
+                "Interpreter {} is itself a script",
+                interpreter.path
+            ));
+        }
+        (format.load)(
+            registry,
+            fs,
+            &interpreter,
+            &interpreter_args,
+            frame_allocator,
+        )
+    }
+}
+
+#[test_case]
+fn parse_shebang_splits_argument() {
+    assert_eq!(
+        parse_shebang(b"#!/bin/sh\necho hi\n"),
+        Some(Shebang {
+            interpreter: "/bin/sh",
+            argument: None,
+        })
+    );
+    assert_eq!(
+        parse_shebang(b"#! /bin/awk -f -v x=1\n"),
+        Some(Shebang {
+            interpreter: "/bin/awk",
+            argument: Some("-f -v x=1"),
+        })
+    );
+    assert_eq!(parse_shebang(b"#!/bin/sh"), None);
+    assert_eq!(parse_shebang(b"\x7fELF"), None);
+}
@@ -0,0 +1,372 @@
+use core::fmt::Display;
+
+use alloc::{vec, vec::Vec};
+use anyhow::anyhow;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    file_system::{page_cache::evict_unused, ATABus, BusDrive, SECTOR_SIZE},
+    memory::{allocator::BootInfoFrameAllocator, phys_to_virt, with_mapper},
+};
+
+use super::{
+    paging::{translate_4kib, COPY_ON_WRITE},
+    process::{Backing, MemoryRegion, Process},
+};
+
+const SECTORS_PER_SLOT: usize = Size4KiB::SIZE as usize / SECTOR_SIZE;
+/// A swap drive starts with a sector holding this, a space and the number
+/// of sectors set aside for swap after it in decimal, like
+/// `POLLSWAP 32767\n`. See `just generate_swap`.
+pub const SWAP_MAGIC: &[u8] = b"POLLSWAP";
+/// Pages are evicted when a fault finds fewer free frames than this, so
+/// there is one for the page and any page tables it needs.
+const MIN_FREE_FRAMES: usize = 4;
+
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+
+/// Swap usage, see [`swap_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SwapStats {
+    pub slots: usize,
+    pub used_slots: usize,
+    /// Pages written out so far.
+    pub pages_out: usize,
+    /// Pages read back in so far.
+    pub pages_in: usize,
+}
+
+impl Display for SwapStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Swap: {} of {} slots used, {} pages out, {} pages in",
+            self.used_slots, self.slots, self.pages_out, self.pages_in
+        )
+    }
+}
+
+/// Page-sized slots on a whole ATA drive, or a part of one.
+struct SwapArea {
+    ata: &'static ATABus,
+    drive: BusDrive,
+    first_sector: usize,
+    /// One bit per slot, set when it holds a page.
+    used: Vec<u64>,
+    /// Address of the page the clock hand last looked at.
+    hand: u64,
+    stats: SwapStats,
+}
+
+impl SwapArea {
+    fn allocate_slot(&mut self) -> Option<usize> {
+        let slot = self.used.iter().enumerate().find_map(|(word, bits)| {
+            (*bits != u64::MAX)
+                .then(|| word * 64 + bits.trailing_ones() as usize)
+        })?;
+        if slot >= self.stats.slots {
+            return None;
+        }
+        self.used[slot / 64] |= 1 << (slot % 64);
+        self.stats.used_slots += 1;
+        Some(slot)
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        self.used[slot / 64] &= !(1 << (slot % 64));
+        self.stats.used_slots -= 1;
+    }
+
+    fn sector(&self, slot: usize) -> usize {
+        self.first_sector + slot * SECTORS_PER_SLOT
+    }
+}
+
+/// The number of swap sectors in a swap header, `None` if `sector` isn't
+/// one.
+pub fn parse_swap_header(sector: &[u8]) -> Option<usize> {
+    let rest = sector.strip_prefix(SWAP_MAGIC)?.strip_prefix(b" ")?;
+    let len = rest.iter().position(|&b| b == b'\n')?;
+    core::str::from_utf8(&rest[..len]).ok()?.parse().ok()
+}
+
+/// Swaps to `drive` of `ata`, which holds `drive_sectors` sectors. The
+/// drive has to start with a swap header, anything else is refused so no
+/// file system is overwritten by accident. Swap takes the sectors the
+/// header sets aside after it.
+pub fn init_swap(
+    ata: &'static ATABus,
+    drive: BusDrive,
+    drive_sectors: usize,
+) -> anyhow::Result<SwapStats> {
+    let mut header = [0u8; SECTOR_SIZE];
+    ata.read(&mut header, drive, 0, 1)?;
+    let sectors = parse_swap_header(&header)
+        .ok_or(anyhow!("{:?} has no swap header", drive))?;
+    if sectors >= drive_sectors {
+        return Err(anyhow!(
+            "Swap header claims {} sectors, {:?} has {}",
+            sectors,
+            drive,
+            drive_sectors
+        ));
+    }
+    let slots = sectors / SECTORS_PER_SLOT;
+    let area = SwapArea {
+        ata,
+        drive,
+        first_sector: 1,
+        used: vec![0; slots.div_ceil(64)],
+        hand: 0,
+        stats: SwapStats {
+            slots,
+            ..Default::default()
+        },
+    };
+    let stats = area.stats;
+    without_interrupts(|| *SWAP.lock() = Some(area));
+    Ok(stats)
+}
+
+/// Usage of the swap area, `None` if there is none.
+pub fn swap_stats() -> Option<SwapStats> {
+    without_interrupts(|| SWAP.lock().as_ref().map(|swap| swap.stats))
+}
+
+/// Frees memory if free frames run low: first file pages no longer mapped
+/// anywhere, which can be read again, then a page of `process` is swapped
+/// out.
+pub(super) fn reclaim(process: &mut Process) {
+    if BootInfoFrameAllocator::shared().free_frames() >= MIN_FREE_FRAMES {
+        return;
+    }
+    if evict_unused() == 0 {
+        // Without swap the allocation that follows fails on its own.
+        let _ = evict_page(process);
+    }
+}
+
+/// Whether pages of `region` can be swapped out.
+fn swappable(region: &MemoryRegion) -> bool {
+    matches!(region.backing, Backing::Anonymous | Backing::Stack { .. })
+}
+
+/// The next swappable page of `process` after `addr` in address order,
+/// wrapping around to the first one.
+fn next_page(process: &Process, addr: u64) -> Option<u64> {
+    let regions = || process.regions.iter().filter(|r| swappable(r));
+    regions()
+        .filter_map(|r| {
+            let next = r.start.max(addr + Size4KiB::SIZE);
+            (next < r.end).then_some(next)
+        })
+        .min()
+        .or_else(|| regions().map(|r| r.start).min())
+}
+
+/// Writes a cold anonymous page of `process` to swap and frees its frame.
+/// The clock hand sweeps the present pages; a page accessed since it last
+/// passed gets a second chance and only has its accessed bit cleared.
+/// Runs on the fault path, so the regions are walked in place.
+pub fn evict_page(process: &mut Process) -> anyhow::Result<()> {
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().ok_or(anyhow!("No swap area"))?;
+
+    let pages: u64 = process
+        .regions
+        .iter()
+        .filter(|r| swappable(r))
+        .map(|r| r.size() / Size4KiB::SIZE)
+        .sum();
+    let mut frame_allocator = BootInfoFrameAllocator::shared();
+
+    // Two rounds: the first may only clear accessed bits.
+    for _ in 0..2 * pages {
+        let Some(addr) = next_page(process, swap.hand) else {
+            break;
+        };
+        swap.hand = addr;
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let translated =
+            with_mapper(|mapper| translate_4kib(mapper, page.start_address()));
+        let Ok((frame, flags)) = translated else {
+            continue;
+        };
+        if flags.contains(COPY_ON_WRITE)
+            || frame_allocator.reference_count(frame) > 1
+        {
+            continue;
+        }
+        if flags.contains(PageTableFlags::ACCESSED) {
+            with_mapper(|mapper| unsafe {
+                mapper
+                    .update_flags(page, flags - PageTableFlags::ACCESSED)
+                    .map(|flush| flush.flush())
+                    .map_err(|e| anyhow!("Failed to age {:?}: {:?}", page, e))
+            })?;
+            continue;
+        }
+
+        let slot = swap.allocate_slot().ok_or(anyhow!("Swap is full"))?;
+        if let Err(e) = write_slot(swap, slot, frame) {
+            swap.free_slot(slot);
+            return Err(e);
+        }
+        with_mapper(|mapper| {
+            mapper
+                .unmap(page)
+                .map(|(_, flush)| flush.flush())
+                .map_err(|e| anyhow!("Failed to unmap {:?}: {:?}", page, e))
+        })?;
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        process.swapped.insert(addr, slot);
+        swap.stats.pages_out += 1;
+        return Ok(());
+    }
+    Err(anyhow!("No page to evict"))
+}
+
+/// Reads `page` back from `slot`, maps it with `flags` and frees the slot.
+pub(super) fn swap_in(
+    slot: usize,
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+) -> anyhow::Result<()> {
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().ok_or(anyhow!("No swap area"))?;
+    let mut frame_allocator = BootInfoFrameAllocator::shared();
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(anyhow!("Out of physical memory"))?;
+    let mapped = read_slot(swap, slot, frame).and_then(|()| {
+        with_mapper(|mapper| unsafe {
+            mapper
+                .map_to(page, frame, flags, &mut frame_allocator)
+                .map(|flush| flush.flush())
+                .map_err(|e| anyhow!("Failed to map {:?}: {:?}", page, e))
+        })
+    });
+    if mapped.is_err() {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        return mapped;
+    }
+    swap.free_slot(slot);
+    swap.stats.pages_in += 1;
+    Ok(())
+}
+
+/// Copies the page in `slot` into `buffer` and leaves it swapped out, for
+/// core dumps.
+pub(super) fn read_swapped(
+    slot: usize,
+    buffer: &mut [u8],
+) -> anyhow::Result<()> {
+    let swap = SWAP.lock();
+    let swap = swap.as_ref().ok_or(anyhow!("No swap area"))?;
+    swap.ata
+        .read(buffer, swap.drive, swap.sector(slot), SECTORS_PER_SLOT)?;
+    Ok(())
+}
+
+fn write_slot(
+    swap: &SwapArea,
+    slot: usize,
+    frame: PhysFrame<Size4KiB>,
+) -> anyhow::Result<()> {
+    let memory = frame_memory(frame)?;
+    swap.ata
+        .write(memory, swap.drive, swap.sector(slot), SECTORS_PER_SLOT)?;
+    Ok(())
+}
+
+fn read_slot(
+    swap: &SwapArea,
+    slot: usize,
+    frame: PhysFrame<Size4KiB>,
+) -> anyhow::Result<()> {
+    let memory = frame_memory(frame)?;
+    swap.ata
+        .read(memory, swap.drive, swap.sector(slot), SECTORS_PER_SLOT)?;
+    Ok(())
+}
+
+fn frame_memory(
+    frame: PhysFrame<Size4KiB>,
+) -> anyhow::Result<&'static mut [u8]> {
+    let virt = phys_to_virt(frame.start_address())
+        .ok_or(anyhow!("Paging is not set up"))?;
+    Ok(unsafe {
+        core::slice::from_raw_parts_mut(
+            virt.as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        )
+    })
+}
+
+#[test_case]
+fn swap_slots_are_reused() {
+    let ata = alloc::boxed::Box::leak(alloc::boxed::Box::new(ATABus::new(
+        0x170, 0x376,
+    )));
+    let mut area = SwapArea {
+        ata,
+        drive: BusDrive::Master,
+        first_sector: 16,
+        used: vec![0; 2],
+        hand: 0,
+        stats: SwapStats {
+            slots: 70,
+            ..Default::default()
+        },
+    };
+    for slot in 0..70 {
+        assert_eq!(area.allocate_slot(), Some(slot));
+    }
+    assert_eq!(area.allocate_slot(), None);
+    area.free_slot(3);
+    assert_eq!(area.stats.used_slots, 69);
+    assert_eq!(area.allocate_slot(), Some(3));
+    assert_eq!(area.sector(3), 16 + 3 * SECTORS_PER_SLOT);
+}
+
+#[test_case]
+fn swap_header_needs_magic_and_size() {
+    let mut sector = [0u8; SECTOR_SIZE];
+    sector[..15].copy_from_slice(b"POLLSWAP 32767\n");
+    assert_eq!(parse_swap_header(&sector), Some(32767));
+    assert_eq!(parse_swap_header(b"POLLSWAP\n"), None);
+    assert_eq!(parse_swap_header(b"POLLSWAP many\n"), None);
+    // A FAT boot sector starts with a jump instead.
+    assert_eq!(parse_swap_header(b"\xeb\x3c\x90mkfs.fat"), None);
+}
+
+#[test_case]
+fn clock_hand_skips_guard_pages_and_wraps() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut process = Process::new("/test.elf");
+    process.add_region(0x40_0000, 0x40_2000, flags);
+    process.add_vma(MemoryRegion::new(
+        0x7fff_f000,
+        0x8000_0000,
+        flags,
+        Backing::Stack { limit: 0x7ff0_0000 },
+    ));
+    process.add_vma(MemoryRegion::new(
+        0x50_0000,
+        0x50_1000,
+        PageTableFlags::empty(),
+        Backing::Guard,
+    ));
+    assert_eq!(next_page(&process, 0), Some(0x40_0000));
+    assert_eq!(next_page(&process, 0x40_0000), Some(0x40_1000));
+    assert_eq!(next_page(&process, 0x40_1000), Some(0x7fff_f000));
+    assert_eq!(next_page(&process, 0x7fff_f000), Some(0x40_0000));
+}
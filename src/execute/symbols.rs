@@ -0,0 +1,299 @@
+use alloc::{string::String, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{
+    file_system::{File, FileSystem, StorageFormat},
+    serial_println,
+};
+
+use super::{
+    elf64::{read_file, ELF64Header, ElfError},
+    process::CURRENT_PROCESS,
+};
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const STT_FUNC: u8 = 2;
+pub const STB_WEAK: u8 = 2;
+pub const SHN_UNDEF: u16 = 0;
+
+/// Symbols beyond this are dropped to keep the kernel heap usable.
+pub const MAX_SYMBOLS: usize = 1024;
+const MAX_SYMBOL_NAME: usize = 64;
+/// Longest section read by `load_symbols`, larger ones are truncated.
+pub const MAX_SECTION_SIZE: usize = 256 * 1024;
+const MAX_FRAMES: usize = 16;
+
+lazy_static! {
+    /// Function symbols of the running user program and its interpreter.
+    pub static ref USER_SYMBOLS: Mutex<SymbolTable> =
+        Mutex::new(SymbolTable::new());
+}
+
+#[derive(Debug, Clone)]
+#[repr(C, packed)]
+pub struct ELF64SectionHeader {
+    name: u32,
+    section_type: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    alignment: u64,
+    entry_size: u64,
+}
+
+#[derive(Debug, Clone)]
+#[repr(C, packed)]
+pub struct ELF64Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section_index: u16,
+    value: u64,
+    size: u64,
+}
+
+impl ELF64Symbol {
+    /// Address of the symbol moved by `load_bias`. Undefined weak symbols
+    /// are zero, other undefined symbols `None`.
+    pub fn address(&self, load_bias: u64) -> Option<u64> {
+        if self.section_index != SHN_UNDEF {
+            Some(self.value.wrapping_add(load_bias))
+        } else if self.info >> 4 == STB_WEAK {
+            Some(0)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub start: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+/// Function symbols sorted by address.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub const fn new() -> Self {
+        Self {
+            symbols: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.symbols.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn insert(&mut self, symbol: Symbol) {
+        let index = self.symbols.partition_point(|s| s.start <= symbol.start);
+        self.symbols.insert(index, symbol);
+    }
+
+    /// The function containing `addr` and the offset into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|s| s.start <= addr);
+        let symbol = &self.symbols[index.checked_sub(1)?];
+        let offset = addr - symbol.start;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((&symbol.name, offset))
+    }
+}
+
+/// Reads the `STT_FUNC` entries of the `.symtab` section of `file` into
+/// `table`, moved by `load_bias`. Stripped files simply add nothing. The
+/// section headers, `.symtab` and `.strtab` are read once each and parsed
+/// in memory, up to [`MAX_SECTION_SIZE`] bytes of each.
+pub fn load_symbols<'a, T: StorageFormat<'a>>(
+    fs: &FileSystem<'a, T>,
+    file: &File,
+    header: &ELF64Header,
+    load_bias: u64,
+    table: &mut SymbolTable,
+) -> Result<(), ElfError> {
+    let (offset, entry_size, count) = header.section_headers();
+    let section_size = core::mem::size_of::<ELF64SectionHeader>();
+    if count == 0 || (entry_size as usize) < section_size {
+        return Ok(());
+    }
+    let headers_size = entry_size as u64 * count as u64;
+    let sections = read_section_bytes(fs, file, offset, headers_size)?;
+    let section = |index: u32| -> Result<ELF64SectionHeader, ElfError> {
+        let start = index as usize * entry_size as usize;
+        let bytes = sections
+            .get(start..start + section_size)
+            .ok_or(ElfError::InvalidSection(index))?;
+        Ok(unsafe {
+            core::ptr::read_unaligned(
+                bytes.as_ptr() as *const ELF64SectionHeader
+            )
+        })
+    };
+
+    let mut symtab = None;
+    for index in 0..count as u32 {
+        let section = section(index)?;
+        if section.section_type == SHT_SYMTAB {
+            symtab = Some(section);
+            break;
+        }
+    }
+    let Some(symtab) = symtab else {
+        return Ok(());
+    };
+    if symtab.link >= count as u32 {
+        return Err(ElfError::InvalidSection(symtab.link));
+    }
+    let strtab = section(symtab.link)?;
+
+    let symbol_size = core::mem::size_of::<ELF64Symbol>();
+    let symtab_entry_size = symtab.entry_size as usize;
+    if symtab_entry_size < symbol_size {
+        return Err(ElfError::InvalidSection(symtab.link));
+    }
+    let symbols = read_section_bytes(fs, file, symtab.offset, symtab.size)?;
+    let names = read_section_bytes(fs, file, strtab.offset, strtab.size)?;
+    for entry in symbols.chunks_exact(symtab_entry_size) {
+        if table.len() >= MAX_SYMBOLS {
+            break;
+        }
+        let symbol = unsafe {
+            core::ptr::read_unaligned(entry.as_ptr() as *const ELF64Symbol)
+        };
+        if symbol.info & 0xf != STT_FUNC || symbol.value == 0 {
+            continue;
+        }
+
+        let Some(name) = names.get(symbol.name as usize..) else {
+            continue;
+        };
+        let name = &name[..name.len().min(MAX_SYMBOL_NAME)];
+        let name = match name.iter().position(|&b| b == 0) {
+            Some(nul) => &name[..nul],
+            None => name,
+        };
+        table.insert(Symbol {
+            start: symbol.value.wrapping_add(load_bias),
+            size: symbol.size,
+            name: String::from_utf8_lossy(name).into_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Reads `size` bytes from `offset` of `file`, cut off at the end of the
+/// file and at [`MAX_SECTION_SIZE`].
+fn read_section_bytes<'a, T: StorageFormat<'a>>(
+    fs: &FileSystem<'a, T>,
+    file: &File,
+    offset: u64,
+    size: u64,
+) -> Result<Vec<u8>, ElfError> {
+    let len = (file.size as u64)
+        .saturating_sub(offset)
+        .min(size)
+        .min(MAX_SECTION_SIZE as u64);
+    let mut bytes = alloc::vec![0u8; len as usize];
+    if len > 0 {
+        read_file(fs, file, &mut bytes, offset as usize)?;
+    }
+    Ok(bytes)
+}
+
+/// Whether an exception interrupted user mode.
+pub fn is_user_fault(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment.0 & 0b11 == 3
+}
+
+/// Prints the faulting instruction and a frame pointer backtrace of a
+/// crashed user program over serial. `rbp` is the user frame pointer at the
+/// time of the fault. Only frames inside the user stack as it has grown so
+/// far are followed, so the walk can't fault on unmapped memory.
+pub fn print_user_backtrace(rip: u64, rbp: u64) {
+    let symbols = USER_SYMBOLS.lock();
+    serial_println!("USER CRASH at {}", Symbolized(&symbols, rip));
+
+    // The crashed code may hold the process, then there is no stack to walk.
+    let Some((stack_bottom, stack_top)) =
+        CURRENT_PROCESS.try_lock().and_then(|process| {
+            let stack = process.as_ref()?.stack()?;
+            Some((stack.start, stack.end))
+        })
+    else {
+        return;
+    };
+    let mut frame = rbp;
+    for depth in 0..MAX_FRAMES {
+        let aligned = frame & 0x7 == 0;
+        if !aligned
+            || frame < stack_bottom
+            || frame.checked_add(16).is_none_or(|end| end > stack_top)
+        {
+            break;
+        }
+        let (next, return_addr) = unsafe {
+            let ptr = frame as *const u64;
+            (ptr.read(), ptr.add(1).read())
+        };
+        if return_addr == 0 {
+            break;
+        }
+        serial_println!("  #{} {}", depth, Symbolized(&symbols, return_addr));
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+}
+
+struct Symbolized<'a>(&'a SymbolTable, u64);
+
+impl core::fmt::Display for Symbolized<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0.lookup(self.1) {
+            Some((name, offset)) => {
+                write!(f, "{:#x} <{}+{:#x}>", self.1, name, offset)
+            }
+            None => write!(f, "{:#x} <unknown>", self.1),
+        }
+    }
+}
+
+#[test_case]
+fn symbol_table_lookup() {
+    let mut table = SymbolTable::new();
+    table.insert(Symbol {
+        start: 0x40_1100,
+        size: 0x20,
+        name: "main".into(),
+    });
+    table.insert(Symbol {
+        start: 0x40_1000,
+        size: 0x11,
+        name: "_start".into(),
+    });
+    assert_eq!(table.lookup(0x40_1005), Some(("_start", 0x5)));
+    assert_eq!(table.lookup(0x40_111f), Some(("main", 0x1f)));
+    assert_eq!(table.lookup(0x40_1011), None);
+    assert_eq!(table.lookup(0x40_0fff), None);
+}
@@ -0,0 +1,210 @@
+use x86_64::{
+    registers::model_specific::FsBase,
+    structures::paging::{OffsetPageTable, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::memory::allocator::BootInfoFrameAllocator;
+
+use super::elf64::{
+    map_user_page, unmap_user_pages, ELF64ProgramHeader, ElfError,
+};
+
+/// Where the TLS block of the first thread is mapped.
+pub const USER_TLS_BASE: u64 = 0x7e00_0000_0000;
+/// Thread control block placed at the thread pointer. Only its first word,
+/// the pointer to itself that `%fs:0` loads, is filled in.
+pub const TCB_SIZE: u64 = 64;
+/// TLS blocks are mapped page aligned, so larger alignments can't be met.
+pub const MAX_TLS_ALIGNMENT: u64 = 4096;
+/// Most memory one thread's TLS block may be mapped with.
+pub const MAX_TLS_SIZE: u64 = 1024 * 1024;
+
+pub const ARCH_SET_FS: u32 = 0x1002;
+pub const ARCH_GET_FS: u32 = 0x1003;
+
+pub const TLS_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// The `PT_TLS` initialization image of a loaded program: `file_size`
+/// bytes of `.tdata` at `addr`, followed by zeroed `.tbss` up to
+/// `memory_size`.
+#[derive(Debug, Clone, Copy)]
+pub struct TlsTemplate {
+    pub addr: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub alignment: u64,
+}
+
+impl TlsTemplate {
+    /// The template described by the `PT_TLS` `program_header`. Its image
+    /// has to lie within one of the loaded `segments`, which are already
+    /// moved by `load_bias`, and its block must fit [`MAX_TLS_SIZE`].
+    pub fn new(
+        program_header: &ELF64ProgramHeader,
+        load_bias: u64,
+        segments: &[ELF64ProgramHeader],
+    ) -> Result<Self, ElfError> {
+        let alignment = program_header.alignment.max(1);
+        let file_size = program_header.file_image_size;
+        let memory_size = program_header.memory_size;
+        let aligned_size = memory_size.checked_add(alignment - 1);
+        if file_size > memory_size
+            || !alignment.is_power_of_two()
+            || alignment > MAX_TLS_ALIGNMENT
+            || aligned_size.is_none_or(|size| size > MAX_TLS_SIZE)
+        {
+            return Err(ElfError::InvalidTls);
+        }
+        let addr = program_header
+            .virt_addr
+            .checked_add(load_bias)
+            .ok_or(ElfError::InvalidTls)?;
+        let end = addr.checked_add(file_size).ok_or(ElfError::InvalidTls)?;
+        let loaded = segments.iter().any(|segment| {
+            segment.virt_addr <= addr
+                && end <= segment.virt_addr + segment.memory_size
+        });
+        let template = Self {
+            addr,
+            file_size,
+            memory_size,
+            alignment,
+        };
+        if !loaded || template.mapped_size() > MAX_TLS_SIZE {
+            return Err(ElfError::InvalidTls);
+        }
+        Ok(template)
+    }
+
+    /// Distance from the start of a block to the thread pointer. x86_64
+    /// uses TLS variant II, so the variables sit right below the TCB.
+    pub fn thread_pointer_offset(&self) -> u64 {
+        (self.memory_size + self.alignment - 1) & !(self.alignment - 1)
+    }
+
+    /// Size of one thread's TLS block including its TCB.
+    pub fn block_size(&self) -> u64 {
+        self.thread_pointer_offset() + TCB_SIZE
+    }
+
+    /// Size of one thread's TLS block rounded up to whole pages.
+    pub fn mapped_size(&self) -> u64 {
+        (self.block_size() + 0xfff) & !0xfff
+    }
+
+    /// Fills `block`, mapped at `block_addr`, with the template and the
+    /// TCB self pointer. Returns the thread pointer.
+    pub fn initialize(
+        &self,
+        template: &[u8],
+        block: &mut [u8],
+        block_addr: u64,
+    ) -> u64 {
+        let tp_offset = self.thread_pointer_offset() as usize;
+        let thread_pointer = block_addr + tp_offset as u64;
+        block.fill(0);
+        block[..template.len()].copy_from_slice(template);
+        block[tp_offset..tp_offset + 8]
+            .copy_from_slice(&thread_pointer.to_le_bytes());
+        thread_pointer
+    }
+}
+
+/// Maps and initializes a TLS block for a new thread at `base`, which has
+/// to be page aligned. The template must already be loaded. Returns the
+/// thread pointer to load into the FS base.
+pub fn allocate_tls(
+    template: &TlsTemplate,
+    base: VirtAddr,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<VirtAddr, ElfError> {
+    if template.memory_size > MAX_TLS_SIZE
+        || template.mapped_size() > MAX_TLS_SIZE
+    {
+        return Err(ElfError::InvalidTls);
+    }
+    let size = template.block_size();
+    let start_page: Page<Size4KiB> = Page::containing_address(base);
+    let end_page =
+        Page::containing_address(base + (template.mapped_size() - 1));
+    for page in Page::range_inclusive(start_page, end_page) {
+        let mapped = map_user_page(page, TLS_FLAGS, mapper, frame_allocator);
+        if let Err(e) = mapped {
+            let pages = Page::range(start_page, page);
+            unmap_user_pages(pages, mapper, frame_allocator);
+            return Err(e);
+        }
+    }
+
+    let (source, block) = unsafe {
+        (
+            core::slice::from_raw_parts(
+                template.addr as *const u8,
+                template.file_size as usize,
+            ),
+            core::slice::from_raw_parts_mut(
+                base.as_mut_ptr::<u8>(),
+                size as usize,
+            ),
+        )
+    };
+    let thread_pointer = template.initialize(source, block, base.as_u64());
+    Ok(VirtAddr::new(thread_pointer))
+}
+
+/// Points `%fs` of the current thread at `thread_pointer`.
+pub fn set_fs_base(thread_pointer: VirtAddr) {
+    FsBase::write(thread_pointer);
+}
+
+#[test_case]
+fn tls_block_layout() {
+    let template = TlsTemplate {
+        addr: 0,
+        file_size: 4,
+        memory_size: 12,
+        alignment: 16,
+    };
+    assert_eq!(template.thread_pointer_offset(), 16);
+    assert_eq!(template.block_size(), 16 + TCB_SIZE);
+
+    let mut block = [0xffu8; 16 + TCB_SIZE as usize];
+    let tp = template.initialize(&[1, 2, 3, 4], &mut block, 0x1000);
+    assert_eq!(tp, 0x1010);
+    assert_eq!(&block[..6], &[1, 2, 3, 4, 0, 0]);
+    assert_eq!(&block[16..24], &0x1010u64.to_le_bytes());
+}
+
+#[test_case]
+fn tls_template_must_be_loaded() {
+    use super::elf64::{ELF64SegmentFlags, PT_LOAD, PT_TLS};
+
+    let header = |segment_type, virt_addr, memory_size| ELF64ProgramHeader {
+        segment_type,
+        segment_flags: ELF64SegmentFlags::READABLE.bits(),
+        offset: 0,
+        virt_addr,
+        phys_addr: virt_addr,
+        file_image_size: 0x10,
+        memory_size,
+        alignment: 8,
+    };
+    let segments = [header(PT_LOAD, 0x40_1000, 0x1000)];
+    let tls = header(PT_TLS, 0x40_1800, 0x20);
+    assert_eq!(
+        TlsTemplate::new(&tls, 0, &segments).unwrap().addr,
+        0x40_1800
+    );
+
+    let outside = header(PT_TLS, 0x40_3000, 0x20);
+    assert!(TlsTemplate::new(&outside, 0, &segments).is_err());
+    let wrapping = header(PT_TLS, u64::MAX - 8, 0x20);
+    assert!(TlsTemplate::new(&wrapping, 0, &segments).is_err());
+    let huge = header(PT_TLS, 0x40_1800, u64::MAX);
+    assert!(TlsTemplate::new(&huge, 0, &segments).is_err());
+}
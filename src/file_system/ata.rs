@@ -120,6 +120,42 @@ impl ATABus {
         Ok(sector_count)
     }
 
+    pub fn write(
+        &self,
+        buffer: &[u8],
+        which: BusDrive,
+        lba_start: usize,
+        sector_count: usize,
+    ) -> Result<usize> {
+        if sector_count == 0 {
+            return Ok(0);
+        }
+
+        self.wait_for_done()?;
+
+        self.drive_select
+            .write(0xE0 | (which as u8) | ((lba_start >> 24) as u8 & 0x0F));
+        self.sector_count.write(sector_count as u8);
+        self.lba_high.write((lba_start >> 16) as u8);
+        self.lba_mid.write((lba_start >> 8) as u8);
+        self.lba_low.write(lba_start as u8);
+        self.command.write(ATACommand::Write as u8);
+
+        let mut buffer_offset = 0;
+        for _lba in lba_start..(lba_start + sector_count) {
+            self.wait_for_ready()?;
+
+            let range = buffer_offset..(buffer_offset + SECTOR_SIZE);
+            for chunk in buffer[range].chunks_exact(2) {
+                self.data.write(u16::from_le_bytes([chunk[0], chunk[1]]));
+            }
+            buffer_offset += SECTOR_SIZE;
+        }
+        self.command.write(ATACommand::CacheFlush as u8);
+        self.wait_for_idle()?;
+        Ok(sector_count)
+    }
+
     pub fn identify(&self, which: BusDrive) -> Result<DriveIdentity> {
         self.wait_for_done()?;
 
@@ -177,6 +213,22 @@ impl ATABus {
         }
     }
 
+    fn wait_for_idle(&self) -> Result<()> {
+        loop {
+            let status = self.status();
+            if status
+                .intersects(ATAStatus::ERROR | ATAStatus::DRIVE_WRITE_FAULT)
+            {
+                return Err(anyhow!(
+                    "Status intersects ERROR | DRIVE WRITE FAULT"
+                ));
+            }
+            if !status.intersects(ATAStatus::BUSY) {
+                return Ok(());
+            }
+        }
+    }
+
     fn wait_for_ready(&self) -> Result<()> {
         let mut _loop_counter = 0;
         loop {
@@ -220,6 +272,11 @@ pub struct DriveIdentity {
 }
 
 impl DriveIdentity {
+    /// Number of sectors addressable with LBA28.
+    pub fn sectors(&self) -> usize {
+        self.user_adressable_sectors as usize
+    }
+
     pub fn new(buffer: [u8; SECTOR_SIZE]) -> Self {
         let general_config = u16::from_le_bytes([buffer[0], buffer[1]]);
         let model_number = [0; 20];
@@ -40,8 +40,19 @@ pub struct BootSector {
     total_sectors_short: u16,
     _media: u8,
     fat_size_sectors: u16,
+    _sectors_per_track: u16,
+    _head_count: u16,
+    _hidden_sectors: u32,
+    total_sectors_long: u32,
 }
 
+const FAT_ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / 2;
+const FAT_FREE: u16 = 0x0000;
+const FAT_END_OF_CHAIN: u16 = 0xFFFF;
+/// Entries from here on mark the end of a chain.
+const FAT_CHAIN_END_MIN: u16 = 0xFFF8;
+const DIR_ENTRY_SIZE: usize = 32;
+
 #[derive(Clone, Debug)]
 pub struct ParsedDirEntry {
     name: String,
@@ -79,6 +90,32 @@ pub struct DirEntry {
 }
 
 impl DirEntry {
+    pub fn new(
+        name: [u8; 11],
+        attributes: FatAttributes,
+        start_cluster: u16,
+        file_size: u32,
+    ) -> Self {
+        let mut _name = [0u8; 8];
+        let mut _ext = [0u8; 3];
+        _name.copy_from_slice(&name[0..8]);
+        _ext.copy_from_slice(&name[8..11]);
+        Self {
+            _name,
+            _ext,
+            attributes: attributes.bits(),
+            _reserved: 0,
+            _creation_time: 0,
+            creation_time: 0,
+            creation_date: 0,
+            accessed_date: 0,
+            _zero: 0,
+            modification_time: 0,
+            modification_date: 0,
+            start_cluster,
+            file_size,
+        }
+    }
     pub fn timestamp(&self) -> TimeStamp {
         let hour = ((self.creation_time >> 11) & 0b11111) as u8;
         let minute = ((self.creation_time >> 5) & 0b111111) as u8;
@@ -190,7 +227,7 @@ impl<'a> FAT16<'a> {
                         (".".to_owned(), None)
                     }
                 } else {
-                    let format = Format83::from_bytes(&buffer[0..11]);
+                    let format = Format83::from_bytes(&chunk[0..11]);
                     (format.0, format.1)
                 };
             lfn_chunks.clear();
@@ -221,9 +258,129 @@ impl<'a> FAT16<'a> {
 
         entries
     }
+
+    fn fat_entry(&self, cluster: u16) -> anyhow::Result<u16> {
+        let index = cluster as usize;
+        let sector = self.boot_sector.reserved_sectors as usize
+            + index / FAT_ENTRIES_PER_SECTOR;
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.ata.read(&mut buf, self.drive, sector, 1)?;
+        let offset = (index % FAT_ENTRIES_PER_SECTOR) * 2;
+        Ok(u16::from_le_bytes([buf[offset], buf[offset + 1]]))
+    }
+
+    /// Writes `value` for `cluster` into every copy of the FAT.
+    fn set_fat_entry(&self, cluster: u16, value: u16) -> anyhow::Result<()> {
+        let index = cluster as usize;
+        let offset = (index % FAT_ENTRIES_PER_SECTOR) * 2;
+        for fat in 0..self.boot_sector.fat_count as usize {
+            let sector = self.boot_sector.reserved_sectors as usize
+                + fat * self.boot_sector.fat_size_sectors as usize
+                + index / FAT_ENTRIES_PER_SECTOR;
+            let mut buf = [0u8; SECTOR_SIZE];
+            self.ata.read(&mut buf, self.drive, sector, 1)?;
+            buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+            self.ata.write(&buf, self.drive, sector, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Finds `count` free clusters in a row. Files are kept contiguous
+    /// because `read_bytes` and `get_content` don't follow cluster chains.
+    fn find_free_clusters(&self, count: usize) -> anyhow::Result<u16> {
+        let last_cluster = self.boot_sector.cluster_count() + 1;
+        let mut buf = [0u8; SECTOR_SIZE];
+        let mut loaded_sector = None;
+        let mut run_start = 2;
+        let mut run_length = 0;
+        for cluster in 2..=last_cluster {
+            let sector = self.boot_sector.reserved_sectors as usize
+                + cluster / FAT_ENTRIES_PER_SECTOR;
+            if loaded_sector != Some(sector) {
+                self.ata.read(&mut buf, self.drive, sector, 1)?;
+                loaded_sector = Some(sector);
+            }
+            let offset = (cluster % FAT_ENTRIES_PER_SECTOR) * 2;
+            let entry = u16::from_le_bytes([buf[offset], buf[offset + 1]]);
+            if entry != FAT_FREE {
+                run_length = 0;
+                continue;
+            }
+            if run_length == 0 {
+                run_start = cluster;
+            }
+            run_length += 1;
+            if run_length == count {
+                return Ok(run_start as u16);
+            }
+        }
+        Err(anyhow!("No room for {} contiguous clusters", count))
+    }
+
+    fn free_chain(&self, start_cluster: u16) -> anyhow::Result<()> {
+        let mut cluster = start_cluster;
+        while (2..FAT_CHAIN_END_MIN).contains(&cluster) {
+            let next = self.fat_entry(cluster)?;
+            self.set_fat_entry(cluster, FAT_FREE)?;
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    /// The root directory slot for `name`: the entry already using the name
+    /// if there is one, otherwise the first free entry. Returns the sector,
+    /// the byte offset into it and the existing entry.
+    fn find_root_slot(
+        &self,
+        name: &[u8; 11],
+    ) -> anyhow::Result<(usize, usize, Option<DirEntry>)> {
+        let (root_sector, root_sectors) =
+            self.boot_sector.calculate_root_dir_offset();
+        let mut free = None;
+        let mut buf = [0u8; SECTOR_SIZE];
+        for sector in root_sector..root_sector + root_sectors {
+            self.ata.read(&mut buf, self.drive, sector, 1)?;
+            for (i, chunk) in buf.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                let offset = i * DIR_ENTRY_SIZE;
+                if chunk[0] == 0x00 {
+                    return free
+                        .or(Some((sector, offset, None)))
+                        .ok_or(anyhow!("Root directory is full"));
+                }
+                if chunk[0] == 0xE5 {
+                    free = free.or(Some((sector, offset, None)));
+                    continue;
+                }
+                if chunk[11] != 0x0F && chunk[0..11] == name[..] {
+                    let entry = unsafe {
+                        core::ptr::read(chunk.as_ptr() as *const DirEntry)
+                    };
+                    return Ok((sector, offset, Some(entry)));
+                }
+            }
+        }
+        free.ok_or(anyhow!("Root directory is full"))
+    }
 }
 
 impl BootSector {
+    fn total_sectors(&self) -> usize {
+        if self.total_sectors_short != 0 {
+            self.total_sectors_short as usize
+        } else {
+            self.total_sectors_long as usize
+        }
+    }
+    fn cluster_count(&self) -> usize {
+        let (root_dir_sector, root_dir_sectors) =
+            self.calculate_root_dir_offset();
+        let data_start = root_dir_sector + root_dir_sectors;
+        let clusters = self.total_sectors().saturating_sub(data_start)
+            / self.sectors_per_cluster as usize;
+        let fat_entries =
+            self.fat_size_sectors as usize * FAT_ENTRIES_PER_SECTOR;
+        clusters.min(fat_entries - 2)
+    }
     fn calculate_root_dir_offset(&self) -> (usize, usize) {
         let root_dir_sector = self.reserved_sectors as usize
             + (self.fat_count as usize * self.fat_size_sectors as usize);
@@ -265,6 +422,7 @@ impl<'a> StorageFormat<'a> for FAT16<'a> {
             files: Vec::new(),
             directories: Vec::new(),
             name: "ROOT".to_owned(),
+            path: String::new(),
         })
     }
     fn boot_sector(&self) -> BootSector {
@@ -286,6 +444,7 @@ impl<'a> StorageFormat<'a> for FAT16<'a> {
                 contents: DoubleVecIndex::new(children),
                 files: Vec::new(),
                 name: entry.name(),
+                path: alloc::format!("{}/{}", directory.path, entry.name()),
                 directories: Vec::new(),
             };
             directory.directories.push(Box::new(dir));
@@ -298,6 +457,7 @@ impl<'a> StorageFormat<'a> for FAT16<'a> {
                 start_cluster: entry.entry.start_cluster,
                 size: entry.entry.file_size as u32,
                 time_stamp: entry.entry.timestamp(),
+                path: alloc::format!("{}/{}", directory.path, entry.name()),
                 name: entry.name,
                 ext: entry.ext.unwrap_or("".to_owned()),
             };
@@ -369,6 +529,89 @@ impl<'a> StorageFormat<'a> for FAT16<'a> {
         }
         Ok(())
     }
+    fn create_file(
+        &self,
+        name: &str,
+        size: usize,
+        fill: &mut dyn FnMut(usize, &mut [u8]),
+    ) -> anyhow::Result<File> {
+        let format = Format83::parse(name)
+            .ok_or(anyhow!("{} is not a valid 8.3 name", name))?;
+        let short_name = format.to_bytes();
+        let (slot_sector, slot_offset, existing) =
+            self.find_root_slot(&short_name)?;
+
+        let sectors_per_cluster = self.boot_sector.sectors_per_cluster as usize;
+        let cluster_size = sectors_per_cluster * SECTOR_SIZE;
+        let cluster_count = size.div_ceil(cluster_size);
+        let start_cluster = if cluster_count == 0 {
+            0
+        } else {
+            self.find_free_clusters(cluster_count)?
+        };
+        for i in 0..cluster_count as u16 {
+            let next = if i as usize + 1 == cluster_count {
+                FAT_END_OF_CHAIN
+            } else {
+                start_cluster + i + 1
+            };
+            self.set_fat_entry(start_cluster + i, next)?;
+        }
+
+        let start_sector = if cluster_count == 0 {
+            0
+        } else {
+            self.boot_sector.cluster_to_sector(start_cluster)
+        };
+        let mut sector_buf = [0u8; SECTOR_SIZE];
+        for sector in 0..cluster_count * sectors_per_cluster {
+            let offset = sector * SECTOR_SIZE;
+            sector_buf.fill(0);
+            if offset < size {
+                let len = core::cmp::min(SECTOR_SIZE, size - offset);
+                fill(offset, &mut sector_buf[..len]);
+            }
+            self.ata.write(
+                &sector_buf,
+                self.drive,
+                start_sector + sector,
+                1,
+            )?;
+        }
+
+        let entry = DirEntry::new(
+            short_name,
+            FatAttributes::ARCHIVE,
+            start_cluster,
+            size as u32,
+        );
+        self.ata.read(&mut sector_buf, self.drive, slot_sector, 1)?;
+        unsafe {
+            core::ptr::write(
+                sector_buf[slot_offset..].as_mut_ptr() as *mut DirEntry,
+                entry,
+            );
+        }
+        self.ata.write(&sector_buf, self.drive, slot_sector, 1)?;
+        // Only now that the entry points at the new chain, so a failure
+        // above leaves the old file intact.
+        if let Some(existing) = existing {
+            self.free_chain(existing.start_cluster)?;
+        }
+
+        Ok(File {
+            start_sector,
+            start_cluster,
+            size: size as u32,
+            time_stamp: TimeStamp::default(),
+            path: match &format.1 {
+                Some(ext) => alloc::format!("/{}.{}", format.0, ext),
+                None => alloc::format!("/{}", format.0),
+            },
+            name: format.0,
+            ext: format.1.unwrap_or_default(),
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -378,6 +621,34 @@ impl Format83 {
     pub fn new(name: String, ext: Option<String>) -> Self {
         Self(name, ext)
     }
+    /// Parses a short name like `core.1` into its upper case 8.3 form.
+    pub fn parse(name: &str) -> Option<Self> {
+        let (name, ext) = match name.split_once('.') {
+            Some((name, ext)) => (name, Some(ext)),
+            None => (name, None),
+        };
+        let valid = |part: &str, max: usize| {
+            !part.is_empty()
+                && part.len() <= max
+                && part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        };
+        if !valid(name, 8) || !ext.is_none_or(|ext| valid(ext, 3)) {
+            return None;
+        }
+        Some(Self(
+            name.to_ascii_uppercase(),
+            ext.map(|ext| ext.to_ascii_uppercase()),
+        ))
+    }
+    /// The space padded on-disk form of the name.
+    pub fn to_bytes(&self) -> [u8; 11] {
+        let mut bytes = [b' '; 11];
+        bytes[..self.0.len()].copy_from_slice(self.0.as_bytes());
+        if let Some(ext) = &self.1 {
+            bytes[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+        }
+        bytes
+    }
     pub fn from_bytes(bytes: &[u8]) -> Self {
         let name = core::str::from_utf8(&bytes[0..8]).unwrap().trim_end();
         let ext = core::str::from_utf8(&bytes[8..11]).unwrap().trim_end();
@@ -411,3 +682,16 @@ impl core::hash::Hash for Format83 {
         self.1.hash(state);
     }
 }
+
+#[test_case]
+fn format83_parses_short_names() {
+    let format = Format83::parse("core.12").unwrap();
+    assert_eq!(&format.to_bytes(), b"CORE    12 ");
+    assert_eq!(
+        &Format83::parse("kernel").unwrap().to_bytes(),
+        b"KERNEL     "
+    );
+    assert!(Format83::parse("toolongname.txt").is_none());
+    assert!(Format83::parse("core.dump").is_none());
+    assert!(Format83::parse(".hidden").is_none());
+}
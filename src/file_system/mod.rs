@@ -2,7 +2,8 @@ use anyhow::anyhow;
 use core::{fmt::Display, marker::PhantomData};
 
 use alloc::{boxed::Box, string::String, vec::Vec};
-use fat16::BootSector;
+use fat16::{BootSector, FAT16};
+use spin::Once;
 
 use crate::{
     print, println, serial_print, serial_println, utils::DoubleVecIndex,
@@ -12,13 +13,57 @@ pub use ata::*;
 pub mod ata;
 pub mod fat16;
 pub mod io;
+pub mod page_cache;
 
 pub const SECTOR_SIZE: usize = 512;
 
-#[derive(Debug)]
+static KERNEL_FS: Once<&'static FileSystem<'static, FAT16<'static>>> =
+    Once::new();
+
+/// Sets the file system the kernel itself uses, for core dumps and paging
+/// in file-backed memory.
+pub fn set_kernel_fs(fs: &'static FileSystem<'static, FAT16<'static>>) {
+    KERNEL_FS.call_once(|| fs);
+}
+
+pub fn kernel_fs() -> Option<&'static FileSystem<'static, FAT16<'static>>> {
+    KERNEL_FS.get().copied()
+}
+
+/// A file system user memory is paged in from, see
+/// `execute::process::Backing::File`.
+pub trait FileSource: Sync {
+    fn read_bytes(
+        &self,
+        file: &File,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> anyhow::Result<()>;
+}
+
+impl<'a, T: StorageFormat<'a>> FileSource for FileSystem<'a, T> {
+    fn read_bytes(
+        &self,
+        file: &File,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> anyhow::Result<()> {
+        self.storage_format.read_bytes(file, buffer, offset)
+    }
+}
+
+impl core::fmt::Debug for dyn FileSource {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "FileSource@{:p}", self)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct File {
     pub name: String,
     pub ext: String,
+    /// Absolute path of the file, e.g. `/folder/subfile.txt`.
+    pub path: String,
     pub start_sector: usize,
     pub start_cluster: u16,
     pub size: u32,
@@ -29,6 +74,10 @@ impl File {
     pub fn name(&self) -> String {
         alloc::format!("{}.{}", self.name, self.ext)
     }
+    /// Path of the directory containing the file, `""` for the root.
+    pub fn directory(&self) -> &str {
+        self.path.rsplit_once('/').map_or("", |(dir, _)| dir)
+    }
 }
 
 #[derive(Debug)]
@@ -37,6 +86,7 @@ pub struct Directory<T: StorageEntry> {
     pub directories: Vec<Box<Directory<T>>>,
     contents: DoubleVecIndex<String, T>,
     name: String,
+    path: String,
 }
 
 impl<T: StorageEntry> Directory<T> {
@@ -76,9 +126,45 @@ impl<'a, T: StorageFormat<'a>> FileSystem<'a, T> {
             }
         }
     }
+    /// Looks up a file by its absolute path, loading every directory on the
+    /// way from the root.
+    pub fn open(&self, path: &str) -> anyhow::Result<File> {
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let name = components
+            .next_back()
+            .ok_or(anyhow!("Invalid path: {}", path))?;
+
+        let mut directory = self.storage_format.get_root()?;
+        for component in components {
+            self.load_directory(component.into(), &mut directory)?;
+            directory = *directory.directories.pop().unwrap();
+        }
+        self.load_file(name.into(), &mut directory)?;
+        Ok(directory.files.pop().unwrap())
+    }
     pub fn get_content(&self, file: &File) -> Vec<u8> {
         self.storage_format.get_content(file).unwrap()
     }
+    pub fn read_bytes(
+        &self,
+        file: &File,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> anyhow::Result<()> {
+        self.storage_format.read_bytes(file, buffer, offset)
+    }
+    /// Creates (or replaces) the file `name` in the root directory. `fill`
+    /// is handed the file offset and the slice to fill with its contents.
+    pub fn create_file(
+        &self,
+        name: &str,
+        size: usize,
+        fill: &mut dyn FnMut(usize, &mut [u8]),
+    ) -> anyhow::Result<File> {
+        let file = self.storage_format.create_file(name, size, fill)?;
+        page_cache::invalidate(self, &file.path);
+        Ok(file)
+    }
     pub fn load_file(
         &self,
         child: String,
@@ -96,7 +182,7 @@ impl<'a, T: StorageFormat<'a>> FileSystem<'a, T> {
     }
 }
 
-pub trait StorageFormat<'a>: Sized {
+pub trait StorageFormat<'a>: Sized + Sync {
     type Entry: StorageEntry;
     fn new(ata_bus: &'a ATABus, drive: BusDrive) -> anyhow::Result<Self>;
     fn boot_sector(&self) -> BootSector;
@@ -114,6 +200,12 @@ pub trait StorageFormat<'a>: Sized {
         buffer: &mut [u8],
         offset: usize,
     ) -> anyhow::Result<()>;
+    fn create_file(
+        &self,
+        name: &str,
+        size: usize,
+        fill: &mut dyn FnMut(usize, &mut [u8]),
+    ) -> anyhow::Result<File>;
 }
 
 pub enum LoadChildResult {
@@ -124,7 +216,7 @@ pub enum LoadChildResult {
 
 pub trait StorageEntry: Display + Into<String> + Clone {}
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct TimeStamp {
     second: u8,
     minute: u8,
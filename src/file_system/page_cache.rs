@@ -0,0 +1,125 @@
+use alloc::{collections::BTreeMap, string::String};
+use anyhow::anyhow;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB,
+    },
+};
+
+use crate::memory::{allocator::BootInfoFrameAllocator, phys_to_virt};
+
+use super::{File, FileSource};
+
+/// A file, by the address of the file system it is on and its path.
+type FileKey = (usize, String);
+
+/// Frames holding pages of files, by file and page index. The cache keeps
+/// one reference to every frame; each mapping of it holds another. Frames
+/// only the cache holds are freed by `evict_unused`.
+static PAGE_CACHE: Mutex<BTreeMap<FileKey, BTreeMap<u64, PhysFrame>>> =
+    Mutex::new(BTreeMap::new());
+
+fn file_key(fs: &dyn FileSource, path: &str) -> FileKey {
+    (
+        fs as *const dyn FileSource as *const () as usize,
+        path.into(),
+    )
+}
+
+/// The frame with page `index` of `file`, read with `fs` if it isn't cached
+/// yet. Past the end of the file the page is zero. The caller gets its own
+/// reference to the frame and drops it with `deallocate_frame`.
+pub fn cached_page(
+    fs: &dyn FileSource,
+    file: &File,
+    index: u64,
+) -> anyhow::Result<PhysFrame> {
+    without_interrupts(|| {
+        let mut cache = PAGE_CACHE.lock();
+        let mut frame_allocator = BootInfoFrameAllocator::shared();
+        let pages = cache.entry(file_key(fs, &file.path)).or_default();
+        if let Some(&frame) = pages.get(&index) {
+            frame_allocator.share_frame(frame);
+            return Ok(frame);
+        }
+
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(anyhow!("Out of physical memory"))?;
+        if let Err(e) = read_page(fs, file, index, frame) {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            return Err(e);
+        }
+        pages.insert(index, frame);
+        frame_allocator.share_frame(frame);
+        Ok(frame)
+    })
+}
+
+/// Drops the cached pages of the file at `path` on `fs`, e.g. after it was
+/// rewritten. Mappings keep the frames they already have.
+pub fn invalidate(fs: &dyn FileSource, path: &str) {
+    let key = file_key(fs, path);
+    let pages = without_interrupts(|| PAGE_CACHE.lock().remove(&key));
+    let mut frame_allocator = BootInfoFrameAllocator::shared();
+    for frame in pages.into_iter().flat_map(|pages| pages.into_values()) {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+/// Frees the cached pages no mapping uses any more, when memory runs low.
+/// Their contents are still on disk, so nothing is written back. Returns
+/// how many were freed, none if the cache is in use.
+pub fn evict_unused() -> usize {
+    without_interrupts(|| {
+        let Some(mut cache) = PAGE_CACHE.try_lock() else {
+            return 0;
+        };
+        let mut frame_allocator = BootInfoFrameAllocator::shared();
+        let mut evicted = 0;
+        for pages in cache.values_mut() {
+            pages.retain(|_, &mut frame| {
+                if frame_allocator.reference_count(frame) > 1 {
+                    return true;
+                }
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                evicted += 1;
+                false
+            });
+        }
+        cache.retain(|_, pages| !pages.is_empty());
+        evicted
+    })
+}
+
+/// Number of file pages in the cache.
+pub fn cached_pages() -> usize {
+    without_interrupts(|| PAGE_CACHE.lock().values().map(BTreeMap::len).sum())
+}
+
+fn read_page(
+    fs: &dyn FileSource,
+    file: &File,
+    index: u64,
+    frame: PhysFrame,
+) -> anyhow::Result<()> {
+    let virt = phys_to_virt(frame.start_address())
+        .ok_or(anyhow!("Paging is not set up"))?;
+    let memory = unsafe {
+        core::slice::from_raw_parts_mut(
+            virt.as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        )
+    };
+    memory.fill(0);
+    let offset = index * Size4KiB::SIZE;
+    let len = (file.size as u64)
+        .saturating_sub(offset)
+        .min(Size4KiB::SIZE);
+    if len > 0 {
+        fs.read_bytes(file, &mut memory[..len as usize], offset as usize)?;
+    }
+    Ok(())
+}
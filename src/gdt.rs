@@ -1,31 +1,125 @@
 #![allow(static_mut_refs)]
+use core::ptr::addr_of;
 use lazy_static::lazy_static;
 use x86_64::instructions::tables::load_tss;
 use x86_64::registers::segmentation::{Segment, CS};
+use x86_64::structures::paging::{mapper::UnmapError, Mapper, Page, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::{structures::gdt::SegmentSelector, VirtAddr};
 
+/// Page faults run on the stack they interrupted. One that hits a guard
+/// page can't push its frame there and becomes a double fault, which has
+/// this stack of its own.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const STACK_SIZE: usize = 4096 * 8;
+/// `kernel_main` runs on a stack this large once it left the bootloader's.
+const MAIN_STACK_SIZE: usize = 4096 * 64;
+const GUARD_SIZE: usize = 4096;
+
+/// A kernel stack with its guard page below it, see `init_guard_pages`.
+#[repr(C, align(4096))]
+struct KernelStack<const SIZE: usize>([u8; GUARD_SIZE], [u8; SIZE]);
+
+impl<const SIZE: usize> KernelStack<SIZE> {
+    const fn new() -> Self {
+        Self([0; GUARD_SIZE], [0; SIZE])
+    }
+}
+
+static mut PRIVILEGE_STACK: KernelStack<STACK_SIZE> = KernelStack::new();
+static mut DOUBLE_FAULT_STACK: KernelStack<STACK_SIZE> = KernelStack::new();
+static mut MAIN_STACK: KernelStack<MAIN_STACK_SIZE> = KernelStack::new();
+
+/// The kernel stacks, by name, with the address of their guard page and
+/// their size.
+fn kernel_stacks() -> [(&'static str, VirtAddr, usize); 3] {
+    [
+        (
+            "privilege",
+            VirtAddr::from_ptr(addr_of!(PRIVILEGE_STACK)),
+            STACK_SIZE,
+        ),
+        (
+            "double fault",
+            VirtAddr::from_ptr(addr_of!(DOUBLE_FAULT_STACK)),
+            STACK_SIZE,
+        ),
+        (
+            "main",
+            VirtAddr::from_ptr(addr_of!(MAIN_STACK)),
+            MAIN_STACK_SIZE,
+        ),
+    ]
+}
+
+fn stack_end(guard: VirtAddr, size: usize) -> VirtAddr {
+    guard + (GUARD_SIZE + size) as u64
+}
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
+        let [privilege, double_fault, _] = kernel_stacks();
 
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        tss.privilege_stack_table[0] = stack_end(privilege.1, privilege.2);
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            stack_end(double_fault.1, double_fault.2);
+        tss
+    };
+}
 
-        // SAFETY: `STACK` is static and only assigned once here
-        let stack_start = VirtAddr::from_ptr(unsafe { STACK.as_ptr() });
-        let stack_end = stack_start + STACK_SIZE as u64;
+/// Leaves the bootloader's stack, which has no guard page the kernel knows
+/// of, and runs `f(arg)` on the guarded main stack. Call it once, after
+/// `init_guard_pages`.
+///
+/// # Safety
+/// Nothing on the current stack may be borrowed by `arg`, it is never
+/// returned to.
+pub unsafe fn run_on_main_stack<T>(
+    arg: &'static T,
+    f: extern "C" fn(&'static T) -> !,
+) -> ! {
+    let [_, _, main] = kernel_stacks();
+    let top = stack_end(main.1, main.2);
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "xor ebp, ebp",
+            "call {f}",
+            "ud2",
+            top = in(reg) top.as_u64(),
+            f = in(reg) f,
+            in("rdi") arg,
+            options(noreturn),
+        )
+    }
+}
 
-        tss.privilege_stack_table[0] = stack_end;
+/// Unmaps the guard page below each kernel stack, so overflowing one
+/// faults instead of overwriting whatever lies below it.
+pub fn init_guard_pages(
+    mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<(), UnmapError> {
+    for (_, guard, _) in kernel_stacks() {
+        mapper.unmap(Page::containing_address(guard))?.1.flush();
+    }
+    Ok(())
+}
 
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end;
-        tss
-    };
+/// Name of the kernel stack whose guard page contains `addr`.
+pub fn guarded_kernel_stack(addr: VirtAddr) -> Option<&'static str> {
+    kernel_stacks()
+        .into_iter()
+        .find(|(_, guard, _)| {
+            *guard <= addr && addr < *guard + GUARD_SIZE as u64
+        })
+        .map(|(name, _, _)| name)
 }
 
-use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+use x86_64::structures::gdt::{
+    Descriptor, DescriptorFlags, GlobalDescriptorTable,
+};
 
 use crate::println;
 lazy_static! {
@@ -38,6 +132,10 @@ lazy_static! {
         let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
         let user_code_selector = gdt.append(Descriptor::user_code_segment());
         let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        // Compatibility mode code segment for 32-bit programs
+        let user_code32_selector = gdt.append(Descriptor::UserSegment(
+            DescriptorFlags::USER_CODE32.bits(),
+        ));
         (
             gdt,
             Selectors {
@@ -46,6 +144,7 @@ lazy_static! {
                 tss_selector,
                 user_code_selector,
                 user_data_selector,
+                user_code32_selector,
             },
         )
     };
@@ -57,6 +156,7 @@ pub struct Selectors {
     pub tss_selector: SegmentSelector,
     pub user_code_selector: SegmentSelector,
     pub user_data_selector: SegmentSelector,
+    pub user_code32_selector: SegmentSelector,
 }
 pub fn init() {
     GDT.0.load();
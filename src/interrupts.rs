@@ -13,7 +13,14 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-use crate::*;
+use crate::{
+    execute::{
+        core_dump::{write_core_dump, FaultRegisters, SIGILL, SIGSEGV},
+        paging::{handle_page_fault, stack_overflow},
+        symbols::{is_user_fault, print_user_backtrace},
+    },
+    *,
+};
 use lazy_static::lazy_static;
 
 lazy_static! {
@@ -73,14 +80,60 @@ pub fn init() {
     IDT.load();
 }
 
+/// Frame pointer of the interrupted code. Every handler starts by pushing
+/// it, since the kernel is built with frame pointers.
+#[inline(always)]
+fn interrupted_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp);
+        *(rbp as *const u64)
+    }
+}
+
+/// Prints a symbolized backtrace and writes a core dump if the exception
+/// came from user mode. `signal` is what the process died of.
+#[inline(always)]
+fn report_user_fault(stack_frame: &InterruptStackFrame, signal: u32) {
+    if is_user_fault(stack_frame) {
+        let rbp = interrupted_frame_pointer();
+        print_user_backtrace(stack_frame.instruction_pointer.as_u64(), rbp);
+        let registers = FaultRegisters::new(stack_frame, rbp);
+        match write_core_dump(signal, &registers) {
+            Ok(file) => {
+                serial_println!("Core dumped to {}", file.path);
+            }
+            Err(e) => {
+                serial_println!("No core dump: {}", e);
+            }
+        }
+    }
+}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    let address = Cr2::read();
+    if let Ok(address) = address {
+        if let Some(task) = stack_overflow(address) {
+            serial_println!("EXCEPTION: stack overflow in task {}", task);
+            serial_println!("{:#?}", stack_frame);
+            report_user_fault(&stack_frame, SIGSEGV);
+            hlt_loop();
+        }
+        match handle_page_fault(address, error_code) {
+            Ok(()) => return,
+            Err(e) => {
+                serial_println!("Page fault not handled: {}", e);
+            }
+        }
+    }
     serial_println!("EXCEPTION: PAGE FAULT");
-    serial_println!("Accessed Address: {:?}", Cr2::read());
+    serial_println!("Accessed Address: {:?}", address);
     serial_println!("Error Code: {:?}", error_code);
     serial_println!("{:#?}", stack_frame);
+    report_user_fault(&stack_frame, SIGSEGV);
     hlt_loop();
 }
 
@@ -115,6 +168,7 @@ extern "x86-interrupt" fn bound_range_exceeded_handler(
 extern "x86-interrupt" fn invalid_opcode_handler(
     stack_frame: InterruptStackFrame,
 ) {
+    report_user_fault(&stack_frame, SIGILL);
     panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
 }
 extern "x86-interrupt" fn device_not_available_handler(
@@ -150,6 +204,7 @@ extern "x86-interrupt" fn general_protection_handler(
     stack_frame: InterruptStackFrame,
     err_code: u64,
 ) {
+    report_user_fault(&stack_frame, SIGSEGV);
     panic!(
         "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nERR_CODE: {}",
         stack_frame, err_code
@@ -233,6 +288,14 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _err_code: u64,
 ) -> ! {
+    // A page fault on a kernel guard page ends up here, see
+    // `gdt::DOUBLE_FAULT_IST_INDEX`.
+    if let Some(stack) = Cr2::read().ok().and_then(gdt::guarded_kernel_stack) {
+        panic!(
+            "EXCEPTION: stack overflow in task kernel ({} stack)\n{:#?}",
+            stack, stack_frame
+        );
+    }
     panic!(
         "EXCEPTION: DOUBLE FAULT\n{:#?}\nERR_CODE: {}",
         stack_frame, _err_code
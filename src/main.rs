@@ -6,19 +6,23 @@
 // don't link the Rust standard library
 #![no_main] // disable all Rust-level entry points
 
-use alloc::borrow::ToOwned;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use pollos::{
-    execute::{elf64::ELF64, Executor},
-    file_system::{fat16::FAT16, ATABus, BusDrive, Directory, FileSystem},
-    memory::{allocator::BootInfoFrameAllocator, init_heap},
+    execute::registry::ExecutorRegistry,
+    file_system::{fat16::FAT16, ATABus, BusDrive, FileSystem},
+    memory::{
+        allocator::{BootInfoFrameAllocator, ALLOCATOR},
+        init_heap,
+    },
     *,
 };
 use x86_64::VirtAddr;
 
 extern crate alloc;
 
+use alloc::boxed::Box;
+
 entry_point!(kernel_main);
 
 /// Kernel Entry Point
@@ -27,21 +31,42 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     pollos::init();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::init(phys_mem_offset) };
+    memory::with_mapper(gdt::init_guard_pages)
+        .expect("stack guard pages failed!");
+    unsafe { gdt::run_on_main_stack(boot_info, kernel_main_guarded) }
+}
+
+/// The rest of `kernel_main`, on a kernel stack with a guard page.
+extern "C" fn kernel_main_guarded(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator =
         unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
-    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed!");
+    memory::with_mapper(|mapper| init_heap(mapper, &mut frame_allocator))
+        .expect("heap init failed!");
+    serial_println!("{}", ALLOCATOR.stats());
 
-    let ata = ATABus::new(0x1f0, 0x3f6);
-    let fs: FileSystem<'_, FAT16> =
-        FileSystem::new(&ata, BusDrive::Slave).expect("Fat init failed!");
+    // The file system lives forever so crashed processes can be dumped to it.
+    let ata: &'static ATABus = Box::leak(Box::new(ATABus::new(0x1f0, 0x3f6)));
+    let fs: &'static FileSystem<'static, FAT16> = Box::leak(Box::new(
+        FileSystem::new(ata, BusDrive::Slave).expect("Fat init failed!"),
+    ));
+    file_system::set_kernel_fs(fs);
 
-    let mut root: Directory<_> = fs.root().expect("Expected root!");
-    fs.load_file("printer.elf".to_owned(), &mut root).unwrap();
-    let file = &root.files[0];
+    // Cold user pages are swapped to the master drive of the secondary bus,
+    // if it carries a swap header.
+    let swap_ata: &'static ATABus =
+        Box::leak(Box::new(ATABus::new(0x170, 0x376)));
+    let swap = swap_ata.identify(BusDrive::Master).and_then(|drive| {
+        execute::swap::init_swap(swap_ata, BusDrive::Master, drive.sectors())
+    });
+    match swap {
+        Ok(stats) => serial_println!("{}", stats),
+        Err(e) => serial_println!("No swap: {}", e),
+    }
 
-    //test_user_stack_setup(&mut mapper, &mut frame_allocator).unwrap();
-    ELF64::load_executable(&fs, file, &mut mapper, &mut frame_allocator)
+    let executors = ExecutorRegistry::new();
+    executors
+        .exec(fs, "/printer.elf", &[], &mut frame_allocator)
         .unwrap();
     hlt_loop();
 }
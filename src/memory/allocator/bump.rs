@@ -1,5 +1,5 @@
 use super::*;
-use core::{alloc::GlobalAlloc, ptr::null_mut};
+use core::{alloc::Layout, ptr::null_mut};
 
 pub struct BumpAllocator {
     heap_start: usize,
@@ -25,30 +25,37 @@ impl BumpAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for Allocator<BumpAllocator> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let mut bump = self.lock();
-
-        let alloc_start = align_up(bump.next, layout.align());
+impl HeapAllocator for BumpAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
         let alloc_end = match alloc_start.checked_add(layout.size()) {
             Some(end) => end,
             None => return null_mut(),
         };
 
-        if alloc_end > bump.heap_end {
+        if alloc_end > self.heap_end {
             null_mut()
         } else {
-            bump.next = alloc_end;
-            bump.allocations += 1;
+            self.next = alloc_end;
+            self.allocations += 1;
             alloc_start as *mut u8
         }
     }
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
-        let mut bump = self.lock();
 
-        bump.allocations -= 1;
-        if bump.allocations == 0 {
-            bump.next = bump.heap_start;
+    unsafe fn deallocate(&mut self, _ptr: *mut u8, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+
+    unsafe fn extend(&mut self, start: usize, size: usize) {
+        if start == self.heap_end {
+            self.heap_end += size;
         }
     }
+
+    fn largest_free_block(&self) -> usize {
+        self.heap_end - self.next
+    }
 }
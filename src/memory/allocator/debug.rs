@@ -0,0 +1,228 @@
+//! Heap debugging enabled by the `heap-debug` feature. Every allocation is
+//! surrounded by redzones that are checked when it is freed, fresh memory
+//! is filled with `ALLOC_POISON` and freed memory with `FREE_POISON`, so
+//! use-after-free reads show up as `0xdd` bytes. Live allocations are
+//! recorded with the return addresses of their callers; resolve them with
+//! `addr2line -e` on the kernel binary.
+
+use core::alloc::Layout;
+
+use spin::Mutex;
+
+use crate::serial_println;
+
+pub const REDZONE_SIZE: usize = 16;
+pub const REDZONE_BYTE: u8 = 0xfd;
+pub const ALLOC_POISON: u8 = 0xcd;
+pub const FREE_POISON: u8 = 0xdd;
+
+const MAX_TRACKED: usize = 2048;
+const CALLERS: usize = 6;
+
+#[derive(Debug, Clone, Copy)]
+struct Tracked {
+    ptr: usize,
+    size: usize,
+    sequence: u64,
+    callers: [usize; CALLERS],
+}
+
+struct Tracker {
+    allocations: [Option<Tracked>; MAX_TRACKED],
+    next_sequence: u64,
+    /// Set once an allocation didn't fit, after which unknown frees can't
+    /// be told apart from untracked ones.
+    overflowed: bool,
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    allocations: [None; MAX_TRACKED],
+    next_sequence: 0,
+    overflowed: false,
+});
+
+fn front_size(layout: Layout) -> usize {
+    REDZONE_SIZE.max(layout.align())
+}
+
+/// The layout actually requested from the heap for `layout`.
+pub fn guarded_layout(layout: Layout) -> Layout {
+    let size = front_size(layout) + layout.size() + REDZONE_SIZE;
+    Layout::from_size_align(size, layout.align()).unwrap()
+}
+
+/// Fills the redzones around the user part of `raw`, a block of
+/// `guarded_layout(layout)`, records it and returns the user pointer.
+///
+/// # Safety
+/// `raw` must be null or a fresh allocation of `guarded_layout(layout)`.
+#[inline(always)]
+pub unsafe fn guard(raw: *mut u8, layout: Layout) -> *mut u8 {
+    if raw.is_null() {
+        return raw;
+    }
+    let front = front_size(layout);
+    unsafe {
+        raw.write_bytes(REDZONE_BYTE, front);
+        let ptr = raw.add(front);
+        ptr.write_bytes(ALLOC_POISON, layout.size());
+        ptr.add(layout.size())
+            .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+        record(ptr as usize, layout.size(), callers());
+        ptr
+    }
+}
+
+/// Checks the redzones of `ptr`, forgets it and poisons the whole block.
+/// Returns the pointer to hand back to the heap. Panics on a corrupted
+/// redzone or a pointer that isn't a live allocation.
+///
+/// # Safety
+/// `ptr` must have been returned by `guard` for `layout`.
+pub unsafe fn unguard(ptr: *mut u8, layout: Layout) -> *mut u8 {
+    let front = front_size(layout);
+    let tracked = forget(ptr as usize);
+    let (raw, before, after) = unsafe {
+        let raw = ptr.sub(front);
+        let before = core::slice::from_raw_parts(raw, front);
+        let after =
+            core::slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE);
+        (raw, before, after)
+    };
+    // Offset of the first bad byte, relative to `ptr`.
+    let corrupted = before
+        .iter()
+        .position(|&b| b != REDZONE_BYTE)
+        .map(|i| i as isize - front as isize)
+        .or_else(|| {
+            let i = after.iter().position(|&b| b != REDZONE_BYTE)?;
+            Some((layout.size() + i) as isize)
+        });
+    if let Some(offset) = corrupted {
+        panic!(
+            "heap redzone of {} byte allocation at {:p} overwritten at \
+             offset {}, allocated from {:x?}",
+            layout.size(),
+            ptr,
+            offset,
+            tracked.map(|t| t.callers)
+        );
+    }
+    unsafe {
+        raw.write_bytes(FREE_POISON, guarded_layout(layout).size());
+    }
+    raw
+}
+
+/// Sequence number of the next allocation, to report only leaks from a
+/// later point with `report_leaks`.
+pub fn allocation_mark() -> u64 {
+    TRACKER.lock().next_sequence
+}
+
+/// Prints every live allocation made since `mark` over serial. Returns
+/// their number.
+pub fn report_leaks(mark: u64) -> usize {
+    let tracker = TRACKER.lock();
+    let mut count = 0;
+    let mut bytes = 0;
+    for tracked in tracker.allocations.iter().flatten() {
+        if tracked.sequence < mark {
+            continue;
+        }
+        count += 1;
+        bytes += tracked.size;
+        serial_println!(
+            "LEAK: {} bytes at {:#x}, allocated from {:x?}",
+            tracked.size,
+            tracked.ptr,
+            tracked.callers
+        );
+    }
+    serial_println!("{} leaked allocations, {} bytes", count, bytes);
+    if tracker.overflowed {
+        serial_println!("(tracking table overflowed, report is incomplete)");
+    }
+    count
+}
+
+fn record(ptr: usize, size: usize, callers: [usize; CALLERS]) {
+    let mut tracker = TRACKER.lock();
+    let sequence = tracker.next_sequence;
+    tracker.next_sequence += 1;
+    match tracker.allocations.iter_mut().find(|t| t.is_none()) {
+        Some(slot) => {
+            *slot = Some(Tracked {
+                ptr,
+                size,
+                sequence,
+                callers,
+            })
+        }
+        None => tracker.overflowed = true,
+    }
+}
+
+fn forget(ptr: usize) -> Option<Tracked> {
+    let mut tracker = TRACKER.lock();
+    let overflowed = tracker.overflowed;
+    let slot = tracker
+        .allocations
+        .iter_mut()
+        .find(|t| t.is_some_and(|t| t.ptr == ptr));
+    match slot {
+        Some(slot) => slot.take(),
+        None if overflowed => None,
+        None => {
+            drop(tracker);
+            panic!("double free or invalid free of {:#x}", ptr);
+        }
+    }
+}
+
+/// Return addresses of the innermost callers, found by following the frame
+/// pointer chain of the kernel stack.
+#[inline(always)]
+fn callers() -> [usize; CALLERS] {
+    let mut callers = [0; CALLERS];
+    let mut frame: usize;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) frame);
+    }
+    for caller in callers.iter_mut() {
+        if frame == 0 || frame & 0x7 != 0 {
+            break;
+        }
+        let (next, return_addr) = unsafe {
+            let ptr = frame as *const usize;
+            (ptr.read(), ptr.add(1).read())
+        };
+        *caller = return_addr;
+        // Callers live further up the stack, close to this frame.
+        if next <= frame || next - frame > 1024 * 1024 {
+            break;
+        }
+        frame = next;
+    }
+    callers
+}
+
+#[test_case]
+fn guard_fills_redzones_and_poisons() {
+    let layout = Layout::from_size_align(10, 8).unwrap();
+    let guarded = guarded_layout(layout);
+    assert_eq!(guarded.size(), REDZONE_SIZE + 10 + REDZONE_SIZE);
+
+    let block = alloc::vec![0u64; guarded.size().div_ceil(8)].leak();
+    let raw = block.as_mut_ptr() as *mut u8;
+    let mark = allocation_mark();
+    let ptr = unsafe { guard(raw, layout) };
+    assert_eq!(ptr as usize, raw as usize + REDZONE_SIZE);
+    assert_eq!(unsafe { *ptr }, ALLOC_POISON);
+    assert_eq!(unsafe { *ptr.add(10) }, REDZONE_BYTE);
+    assert_eq!(report_leaks(mark), 1);
+
+    assert_eq!(unsafe { unguard(ptr, layout) }, raw);
+    assert_eq!(unsafe { *ptr }, FREE_POISON);
+    assert_eq!(report_leaks(mark), 0);
+}
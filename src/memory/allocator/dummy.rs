@@ -1,12 +1,48 @@
-use core::{alloc::GlobalAlloc, ptr::null_mut};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::null_mut,
+};
 
+use super::HeapAllocator;
+
+/// Fails every allocation, selected with the `alloc-dummy` feature.
+#[derive(Default)]
 pub struct Dummy;
 
+impl Dummy {
+    pub const fn new() -> Self {
+        Dummy
+    }
+
+    /// Takes the same arguments as the other allocators, but ignores the
+    /// heap.
+    ///
+    /// # Safety
+    /// Nothing to uphold, it is only `unsafe` to match the other allocators.
+    pub unsafe fn init(&mut self, _heap_start: usize, _heap_size: usize) {}
+}
+
 unsafe impl GlobalAlloc for Dummy {
-    unsafe fn alloc(&self, _layout: core::alloc::Layout) -> *mut u8 {
+    unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
         null_mut()
     }
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
         panic!("Dealloc should never be called!")
     }
 }
+
+impl HeapAllocator for Dummy {
+    fn allocate(&mut self, _layout: Layout) -> *mut u8 {
+        null_mut()
+    }
+
+    unsafe fn deallocate(&mut self, _ptr: *mut u8, _layout: Layout) {
+        panic!("Dealloc should never be called!")
+    }
+
+    unsafe fn extend(&mut self, _start: usize, _size: usize) {}
+
+    fn largest_free_block(&self) -> usize {
+        0
+    }
+}
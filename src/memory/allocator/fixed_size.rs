@@ -0,0 +1,130 @@
+use core::alloc::Layout;
+
+use super::{HeapAllocator, LinkedListAllocator};
+
+/// The block sizes to use.
+///
+/// The sizes must each be a power of 2 because they are also used as the
+/// block alignment. Anything larger goes to the fallback allocator.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Hands out blocks of power-of-two size classes from per-class free lists,
+/// which makes small allocations O(1). Freed blocks go back onto their list
+/// instead of the heap, so memory of a size class is never given back.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        Self {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+        }
+    }
+
+    /// # Safety
+    /// The heap range must be mapped, unused and this may only be called
+    /// once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.fallback_allocator.init(heap_start, heap_size);
+        }
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The size class for `layout`, `None` if it is too large for any.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // No block left, carve a new one out of the heap.
+                    let block_size = BLOCK_SIZES[index];
+                    let layout =
+                        Layout::from_size_align(block_size, block_size)
+                            .unwrap();
+                    self.fallback_allocator.allocate(layout)
+                }
+            },
+            None => self.fallback_allocator.allocate(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // Every block can hold a node: sizes and alignments are at
+                // least that of `ListNode`.
+                assert!(size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                unsafe {
+                    new_node_ptr.write(new_node);
+                    self.list_heads[index] = Some(&mut *new_node_ptr);
+                }
+            }
+            None => unsafe {
+                self.fallback_allocator.deallocate(ptr, layout);
+            },
+        }
+    }
+
+    unsafe fn extend(&mut self, start: usize, size: usize) {
+        unsafe {
+            self.fallback_allocator.extend(start, size);
+        }
+    }
+
+    fn largest_free_block(&self) -> usize {
+        let largest_block = BLOCK_SIZES
+            .iter()
+            .zip(&self.list_heads)
+            .rev()
+            .find(|(_, head)| head.is_some())
+            .map_or(0, |(&size, _)| size);
+        largest_block.max(self.fallback_allocator.largest_free_block())
+    }
+}
+
+#[test_case]
+fn fixed_size_reuses_blocks() {
+    let heap = alloc::vec![0u64; 1024].leak();
+    let mut allocator = FixedSizeBlockAllocator::new();
+    unsafe { allocator.init(heap.as_mut_ptr() as usize, heap.len() * 8) };
+
+    let small = Layout::from_size_align(24, 8).unwrap();
+    let first = allocator.allocate(small);
+    assert_eq!(first as usize % 32, 0);
+    unsafe { allocator.deallocate(first, small) };
+    assert_eq!(allocator.allocate(small), first);
+
+    let large = Layout::from_size_align(4096, 8).unwrap();
+    let block = allocator.allocate(large);
+    assert!(!block.is_null());
+    unsafe { allocator.deallocate(block, large) };
+}
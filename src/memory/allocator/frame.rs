@@ -1,36 +1,244 @@
+use alloc::collections::BTreeMap;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    instructions::interrupts::without_interrupts,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB,
+    },
     PhysAddr,
 };
 
+const FRAME_SIZE: u64 = 4096;
+/// Physical memory above this is never handed out.
+pub const MAX_PHYSICAL_MEMORY: u64 = 4 * 1024 * 1024 * 1024;
+const MAX_FRAMES: usize = (MAX_PHYSICAL_MEMORY / FRAME_SIZE) as usize;
+
+/// One bit per frame below `MAX_PHYSICAL_MEMORY`, set when the frame is in
+/// use. It lives in `.bss` because the allocator is needed before the heap.
+static mut FRAME_BITMAP: [u64; MAX_FRAMES / 64] = [0; MAX_FRAMES / 64];
+
+static FRAMES: Mutex<FrameBitmap> = Mutex::new(FrameBitmap::empty());
+
+/// References beyond the first to frames that are mapped more than once. It
+/// has its own lock, as growing the heap for it takes the `FRAMES` one.
+static SHARED_FRAMES: Mutex<BTreeMap<usize, usize>> =
+    Mutex::new(BTreeMap::new());
+
+/// Locks the frame bitmap. Interrupts are off meanwhile, so a page fault
+/// handler can allocate frames too.
+fn frames<R>(f: impl FnOnce(&mut FrameBitmap) -> R) -> R {
+    without_interrupts(|| f(&mut FRAMES.lock()))
+}
+
+/// Bitmap frame allocator over the usable regions of the bootloader memory
+/// map. Freed frames are reused and runs of contiguous frames can be
+/// allocated for DMA buffers. Frames mapped more than once, e.g. copy-on-write
+/// pages, are reference counted and only freed with their last reference.
+///
+/// The bitmap is global, so this is only a handle to it and the heap can
+/// grow without being passed one.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+    _private: (),
 }
 
 impl BootInfoFrameAllocator {
+    /// # Safety
+    /// The memory map has to be valid and this may only be called once, as
+    /// every allocator shares the same bitmap.
     pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
-        Self {
-            memory_map,
-            next: 0,
-        }
-    }
-    fn usable_frame(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_map
+        let bitmap = unsafe { &mut *core::ptr::addr_of_mut!(FRAME_BITMAP) };
+        let mut frame_bitmap = FrameBitmap::new(bitmap);
+        for region in memory_map
             .iter()
             .filter(|r| r.region_type == MemoryRegionType::Usable)
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            .flat_map(|r| r.step_by(4096))
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        {
+            let start = region.range.start_addr().div_ceil(FRAME_SIZE);
+            let end = region.range.end_addr() / FRAME_SIZE;
+            for frame in start..end.min(MAX_FRAMES as u64) {
+                frame_bitmap.release(frame as usize);
+            }
+        }
+        frames(|frames| *frames = frame_bitmap);
+        Self { _private: () }
+    }
+
+    /// Another handle to the allocator set up by `new`.
+    pub(crate) fn shared() -> Self {
+        Self { _private: () }
+    }
+
+    pub fn free_frames(&self) -> usize {
+        frames(|frames| frames.free_frames)
+    }
+
+    /// Adds a reference to the allocated `frame`, which then takes one more
+    /// `deallocate_frame` to free.
+    pub fn share_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = FrameBitmap::index(frame);
+        without_interrupts(|| {
+            *SHARED_FRAMES.lock().entry(index).or_insert(0) += 1;
+        })
+    }
+
+    /// Number of references to `frame`, zero if it is free.
+    pub fn reference_count(&self, frame: PhysFrame<Size4KiB>) -> usize {
+        let index = FrameBitmap::index(frame);
+        if !frames(|frames| {
+            index / 64 < frames.bitmap.len() && frames.is_used(index)
+        }) {
+            return 0;
+        }
+        without_interrupts(|| {
+            1 + SHARED_FRAMES.lock().get(&index).copied().unwrap_or(0)
+        })
+    }
+
+    /// Allocates `count` physically contiguous frames, the first aligned to
+    /// `alignment` frames.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        alignment: usize,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        frames(|frames| frames.allocate_contiguous(count, alignment))
+    }
+
+    /// Frees `count` frames starting at `frame`.
+    ///
+    /// # Safety
+    /// The frames must have come from `allocate_contiguous` and be unused.
+    pub unsafe fn deallocate_contiguous(
+        &mut self,
+        frame: PhysFrame<Size4KiB>,
+        count: usize,
+    ) {
+        let start = FrameBitmap::index(frame);
+        frames(|frames| {
+            for index in start..start + count {
+                frames.release(index);
+            }
+        })
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frame().nth(self.next);
-        self.next += 1;
-        frame
+        frames(|frames| frames.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Drops a reference to `frame`, freeing it with the last one.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = FrameBitmap::index(frame);
+        let shared = without_interrupts(|| {
+            let mut shared = SHARED_FRAMES.lock();
+            match shared.get_mut(&index) {
+                Some(1) => shared.remove(&index).is_some(),
+                Some(count) => {
+                    *count -= 1;
+                    true
+                }
+                None => false,
+            }
+        });
+        if !shared {
+            frames(|frames| frames.release(index))
+        }
+    }
+}
+
+struct FrameBitmap {
+    bitmap: &'static mut [u64],
+    /// Word to start searching from, everything before it is in use.
+    next: usize,
+    free_frames: usize,
+}
+
+impl FrameBitmap {
+    const fn empty() -> Self {
+        Self {
+            bitmap: &mut [],
+            next: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// A bitmap with every frame in use.
+    fn new(bitmap: &'static mut [u64]) -> Self {
+        bitmap.fill(u64::MAX);
+        Self {
+            bitmap,
+            next: 0,
+            free_frames: 0,
+        }
+    }
+
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        while self.next < self.bitmap.len() {
+            let word = self.bitmap[self.next];
+            if word != u64::MAX {
+                let index = self.next * 64 + word.trailing_ones() as usize;
+                self.claim(index);
+                return Some(Self::frame(index));
+            }
+            self.next += 1;
+        }
+        None
+    }
+
+    fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        alignment: usize,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        if count == 0 || !alignment.is_power_of_two() {
+            return None;
+        }
+        let frame_count = self.bitmap.len() * 64;
+        let mut start = (self.next * 64).next_multiple_of(alignment);
+        while start + count <= frame_count {
+            match (start..start + count).find(|&f| self.is_used(f)) {
+                Some(used) => {
+                    start = (used + 1).next_multiple_of(alignment);
+                }
+                None => {
+                    for frame in start..start + count {
+                        self.claim(frame);
+                    }
+                    return Some(Self::frame(start));
+                }
+            }
+        }
+        None
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn claim(&mut self, index: usize) {
+        debug_assert!(!self.is_used(index));
+        self.bitmap[index / 64] |= 1 << (index % 64);
+        self.free_frames -= 1;
+    }
+
+    fn release(&mut self, index: usize) {
+        if index / 64 >= self.bitmap.len() || !self.is_used(index) {
+            return;
+        }
+        self.bitmap[index / 64] &= !(1 << (index % 64));
+        self.free_frames += 1;
+        self.next = self.next.min(index / 64);
+    }
+
+    fn frame(index: usize) -> PhysFrame<Size4KiB> {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    fn index(frame: PhysFrame<Size4KiB>) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
     }
 }
 
@@ -41,3 +249,29 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
         None
     }
 }
+
+#[test_case]
+fn frame_bitmap_reuses_and_finds_runs() {
+    let bitmap =
+        alloc::boxed::Box::leak(alloc::vec![0u64; 4].into_boxed_slice());
+    let mut frames = FrameBitmap::new(bitmap);
+    for index in 10..200 {
+        frames.release(index);
+    }
+    assert_eq!(frames.free_frames, 190);
+
+    let first = frames.allocate_frame().unwrap();
+    assert_eq!(FrameBitmap::index(first), 10);
+    frames.release(FrameBitmap::index(first));
+    let again = frames.allocate_frame().unwrap();
+    assert_eq!(again, first);
+
+    let run = frames.allocate_contiguous(16, 16).unwrap();
+    assert_eq!(FrameBitmap::index(run), 16);
+    assert_eq!(frames.free_frames, 190 - 17);
+    assert!(frames.allocate_contiguous(200, 1).is_none());
+    for index in 16..32 {
+        frames.release(index);
+    }
+    assert_eq!(frames.free_frames, 190 - 1);
+}
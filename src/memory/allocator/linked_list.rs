@@ -1,11 +1,8 @@
-use core::{
-    alloc::{GlobalAlloc, Layout},
-    ptr::null_mut,
-};
+use core::{alloc::Layout, ptr::null_mut};
 
 use crate::memory::allocator::align_up;
 
-use super::Allocator;
+use super::HeapAllocator;
 
 struct ListNode {
     size: usize,
@@ -41,17 +38,61 @@ impl LinkedListAllocator {
         }
     }
 
+    /// Inserts a region into the free list, which is kept sorted by
+    /// address, merging it with the regions right before and after it.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         assert_eq!(align_up(addr, align_of::<ListNode>()), addr);
         assert!(size >= size_of::<ListNode>());
 
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // The head is the only node of size 0 and never merged into.
+        if current.size != 0 && current.end_addr() == addr {
+            current.size += size;
+            Self::merge_next(current);
+            return;
+        }
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = current.next.take();
         let node_ptr = addr as *mut ListNode;
         unsafe {
             node_ptr.write(node);
-            self.head.next = Some(&mut *node_ptr)
+            Self::merge_next(&mut *node_ptr);
+            current.next = Some(&mut *node_ptr)
+        }
+    }
+
+    /// Absorbs the region after `node` if it directly follows it.
+    fn merge_next(node: &mut ListNode) {
+        if let Some(next) = node.next.take() {
+            if node.end_addr() == next.start_addr() {
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
+    }
+
+    /// Number of free regions and the size of the largest one.
+    pub fn free_regions(&self) -> (usize, usize) {
+        let mut count = 0;
+        let mut largest = 0;
+        let mut current = &self.head;
+        while let Some(region) = current.next.as_deref() {
+            count += 1;
+            largest = largest.max(region.size);
+            current = region;
         }
+        (count, largest)
     }
 
     fn find_region(
@@ -82,7 +123,13 @@ impl LinkedListAllocator {
         size: usize,
         align: usize,
     ) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let gap = alloc_start - region.start_addr();
+        if gap > 0 && gap < size_of::<ListNode>() {
+            // The gap has to fit a node to be handed back.
+            alloc_start =
+                align_up(region.start_addr() + size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -107,18 +154,25 @@ impl LinkedListAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for Allocator<LinkedListAllocator> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
+impl HeapAllocator for LinkedListAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align)
-        {
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            let region_start = region.start_addr();
             let excess_size = region.end_addr() - alloc_end;
             if excess_size > 0 {
                 unsafe {
-                    allocator.add_free_region(alloc_end, excess_size);
+                    self.add_free_region(alloc_end, excess_size);
+                }
+            }
+            if alloc_start > region_start {
+                unsafe {
+                    self.add_free_region(
+                        region_start,
+                        alloc_start - region_start,
+                    );
                 }
             }
             alloc_start as *mut u8
@@ -126,10 +180,54 @@ unsafe impl GlobalAlloc for Allocator<LinkedListAllocator> {
             null_mut()
         }
     }
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let (size, _) = LinkedListAllocator::size_align(layout);
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        unsafe {
+            self.add_free_region(ptr as usize, size);
+        }
+    }
+
+    unsafe fn extend(&mut self, start: usize, size: usize) {
         unsafe {
-            self.lock().add_free_region(ptr as usize, size);
+            self.add_free_region(start, size);
         }
     }
+
+    fn largest_free_block(&self) -> usize {
+        self.free_regions().1
+    }
+}
+
+#[test_case]
+fn linked_list_coalesces_random_frees() {
+    const HEAP_SIZE: usize = 16 * 1024;
+    let heap = alloc::vec![0u64; HEAP_SIZE / 8].leak();
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(heap.as_mut_ptr() as usize, HEAP_SIZE) };
+
+    let mut rng = crate::utils::XorShift(0x2545_f491_4f6c_dd1d);
+    let mut live: alloc::vec::Vec<(*mut u8, Layout)> = alloc::vec::Vec::new();
+    for round in 0..2000 {
+        if live.len() < 32 && rng.next_usize() % 3 != 0 {
+            let size = 1 + rng.next_usize() % 256;
+            let align = 1 << (rng.next_usize() % 7);
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = allocator.allocate(layout);
+            assert!(!ptr.is_null(), "allocation failed in round {}", round);
+            assert_eq!(ptr as usize % align, 0);
+            live.push((ptr, layout));
+        } else if !live.is_empty() {
+            let (ptr, layout) = live.swap_remove(rng.next_usize() % live.len());
+            unsafe { allocator.deallocate(ptr, layout) };
+        }
+        // Every free region sits between two live blocks.
+        assert!(allocator.free_regions().0 <= live.len() + 1);
+    }
+
+    while !live.is_empty() {
+        let (ptr, layout) = live.swap_remove(rng.next_usize() % live.len());
+        unsafe { allocator.deallocate(ptr, layout) };
+    }
+    assert_eq!(allocator.free_regions(), (1, HEAP_SIZE));
 }
@@ -1,25 +1,126 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt::Display,
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::grow_heap;
+
 mod bump;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
 mod dummy;
+mod fixed_size;
 mod frame;
 mod linked_list;
 
 pub use bump::*;
 pub use dummy::*;
+pub use fixed_size::*;
 pub use frame::*;
 pub use linked_list::*;
 
+/// The kernel heap allocator, picked with exactly one of the `alloc-bump`,
+/// `alloc-linked-list`, `alloc-fixed-size` (default) and `alloc-dummy`
+/// features.
+#[cfg(feature = "alloc-bump")]
+pub type KernelAllocator = BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+pub type KernelAllocator = LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-size")]
+pub type KernelAllocator = FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-dummy")]
+pub type KernelAllocator = Dummy;
+
+#[cfg(any(
+    all(
+        feature = "alloc-bump",
+        any(
+            feature = "alloc-linked-list",
+            feature = "alloc-fixed-size",
+            feature = "alloc-dummy"
+        )
+    ),
+    all(
+        feature = "alloc-linked-list",
+        any(feature = "alloc-fixed-size", feature = "alloc-dummy")
+    ),
+    all(feature = "alloc-fixed-size", feature = "alloc-dummy"),
+))]
+compile_error!(
+    "more than one `alloc-*` feature is enabled, pick one with \
+     `--no-default-features --features alloc-<name>`"
+);
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-size",
+    feature = "alloc-dummy"
+)))]
+compile_error!("no `alloc-*` feature is enabled, the kernel needs a heap");
+
 #[global_allocator]
-pub static ALLOCATOR: Allocator<LinkedListAllocator> =
-    Allocator::new(LinkedListAllocator::new());
+pub static ALLOCATOR: Allocator<KernelAllocator> =
+    Allocator::new(KernelAllocator::new());
+
+/// Snapshot of the heap usage, see `Allocator::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes requested by live allocations.
+    pub bytes_in_use: usize,
+    /// Highest `bytes_in_use` so far.
+    pub peak_bytes_in_use: usize,
+    /// Number of live allocations.
+    pub allocations: usize,
+    /// The largest allocation that would currently succeed without growing
+    /// the heap.
+    pub largest_free_block: usize,
+}
+
+impl Display for HeapStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Heap: {} bytes in {} allocations (peak {}), largest free block {}",
+            self.bytes_in_use,
+            self.allocations,
+            self.peak_bytes_in_use,
+            self.largest_free_block
+        )
+    }
+}
+
+/// An allocator the global `Allocator` wrapper can drive and grow.
+pub trait HeapAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` with the same `layout`.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+    /// Hands the allocator the memory `[start, start + size)` directly
+    /// after its heap.
+    ///
+    /// # Safety
+    /// The range must be mapped and unused.
+    unsafe fn extend(&mut self, start: usize, size: usize);
+    /// Size of the largest free block.
+    fn largest_free_block(&self) -> usize;
+}
 
 pub struct Allocator<T> {
     inner: spin::Mutex<T>,
+    bytes_in_use: AtomicUsize,
+    peak_bytes_in_use: AtomicUsize,
+    allocations: AtomicUsize,
 }
 
 impl<T> Allocator<T> {
     pub const fn new(value: T) -> Self {
         Allocator {
             inner: spin::Mutex::new(value),
+            bytes_in_use: AtomicUsize::new(0),
+            peak_bytes_in_use: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
         }
     }
     pub fn lock(&self) -> spin::MutexGuard<T> {
@@ -27,6 +128,73 @@ impl<T> Allocator<T> {
     }
 }
 
+impl<T: HeapAllocator> Allocator<T> {
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            bytes_in_use: self.bytes_in_use.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak_bytes_in_use.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            largest_free_block: self.lock().largest_free_block(),
+        }
+    }
+
+    fn allocate_or_grow(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let ptr = allocator.allocate(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // Out of memory, map more heap until it fits or the cap is hit.
+        while let Some((start, size)) =
+            grow_heap(layout.size() + layout.align())
+        {
+            unsafe { allocator.extend(start, size) };
+            let ptr = allocator.allocate(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+        null_mut()
+    }
+}
+
+unsafe impl<T: HeapAllocator> GlobalAlloc for Allocator<T> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap-debug")]
+        let ptr = unsafe {
+            debug::guard(
+                self.allocate_or_grow(debug::guarded_layout(layout)),
+                layout,
+            )
+        };
+        #[cfg(not(feature = "heap-debug"))]
+        let ptr = self.allocate_or_grow(layout);
+        if !ptr.is_null() {
+            let in_use = self
+                .bytes_in_use
+                .fetch_add(layout.size(), Ordering::Relaxed)
+                + layout.size();
+            self.peak_bytes_in_use.fetch_max(in_use, Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe {
+            #[cfg(feature = "heap-debug")]
+            self.lock().deallocate(
+                debug::unguard(ptr, layout),
+                debug::guarded_layout(layout),
+            );
+            #[cfg(not(feature = "heap-debug"))]
+            self.lock().deallocate(ptr, layout);
+        }
+        self.bytes_in_use
+            .fetch_sub(layout.size(), Ordering::Relaxed);
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 pub fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
@@ -1,40 +1,178 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags,
-        Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
-use super::allocator::ALLOCATOR;
+use super::{
+    allocator::{align_up, BootInfoFrameAllocator, ALLOCATOR},
+    try_with_mapper,
+};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Default for how large the heap may grow, see `set_heap_limit`.
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+/// The heap grows by at least this much at a time.
+const HEAP_GROWTH: usize = 64 * 1024;
+const HEAP_FLAGS: PageTableFlags =
+    PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+const HUGE_PAGE_SIZE: usize = Size2MiB::SIZE as usize;
+
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    if let (_, Some(e)) =
+        map_heap(HEAP_START, HEAP_SIZE, mapper, frame_allocator)
+    {
+        return Err(e);
+    }
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::Relaxed);
+
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+/// Caps how large the heap may grow. It never shrinks below its current
+/// size.
+pub fn set_heap_limit(size: usize) {
+    HEAP_LIMIT.store(size, Ordering::Relaxed);
+}
+
+/// How large the heap may grow, see `set_heap_limit`.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Bytes currently mapped for the heap.
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::Relaxed) - HEAP_START
+}
+
+/// Maps at least `min_size` more bytes after the end of the heap. Returns
+/// the new range, or `None` if the limit is reached, memory ran out or the
+/// mapper is in use. If memory runs out part way, the pages mapped so far
+/// still become part of the heap and are returned, even when they are
+/// fewer than `min_size`. Called by the allocator while it holds its lock.
+pub fn grow_heap(min_size: usize) -> Option<(usize, usize)> {
+    let end = HEAP_END.load(Ordering::Relaxed);
+    let limit = HEAP_START + HEAP_LIMIT.load(Ordering::Relaxed);
+    let size = align_up(min_size.max(HEAP_GROWTH), 4096)
+        .min(limit.saturating_sub(end));
+    if size < min_size {
+        return None;
+    }
+
+    let mut frame_allocator = BootInfoFrameAllocator::shared();
+    let (mapped, _) = try_with_mapper(|mapper| {
+        map_heap_growth(end, size, mapper, &mut frame_allocator)
+    })?;
+    if mapped == 0 {
+        return None;
+    }
+    HEAP_END.store(end + mapped, Ordering::Relaxed);
+    Some((end, mapped))
+}
+
+/// Maps `size` bytes of heap at `start` for `grow_heap`. Every whole 2 MiB
+/// page in the range is mapped as one, backed by contiguous frames, while
+/// such frames can be found; the rest is mapped a frame at a time. Returns
+/// how many bytes were mapped, and the error that stopped it short.
+fn map_heap_growth(
+    start: usize,
+    size: usize,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> (usize, Option<MapToError<Size4KiB>>) {
+    let end = start + size;
+    let huge_start = align_up(start, HUGE_PAGE_SIZE).min(end);
+    let (mut mapped, error) =
+        map_heap(start, huge_start - start, mapper, frame_allocator);
+    if error.is_some() {
+        return (mapped, error);
+    }
+    while end - (start + mapped) >= HUGE_PAGE_SIZE
+        && map_huge_heap_page(start + mapped, mapper, frame_allocator)
+    {
+        mapped += HUGE_PAGE_SIZE;
+    }
+    let (tail, error) =
+        map_heap(start + mapped, size - mapped, mapper, frame_allocator);
+    (mapped + tail, error)
+}
+
+/// Maps a 2 MiB page of heap at `start`. `false` if there are no free
+/// contiguous frames for it or it can't be mapped.
+fn map_huge_heap_page(
+    start: usize,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> bool {
+    const FRAMES: usize = HUGE_PAGE_SIZE / Size4KiB::SIZE as usize;
+
+    let Some(frame) = frame_allocator.allocate_contiguous(FRAMES, FRAMES)
+    else {
+        return false;
+    };
+    let page =
+        Page::<Size2MiB>::containing_address(VirtAddr::new(start as u64));
+    let huge_frame =
+        PhysFrame::<Size2MiB>::containing_address(frame.start_address());
+    match unsafe {
+        mapper.map_to(page, huge_frame, HEAP_FLAGS, frame_allocator)
+    } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_contiguous(frame, FRAMES) };
+            false
+        }
+    }
+}
+
+/// Maps `size` bytes of heap at `start`, a page at a time. Returns how many
+/// bytes were mapped, and the error that stopped it short.
+fn map_heap(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> (usize, Option<MapToError<Size4KiB>>) {
+    if size == 0 {
+        return (0, None);
+    }
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size as u64 - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    let mut mapped = 0;
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags: PageTableFlags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
-
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        let Some(frame) = frame_allocator.allocate_frame() else {
+            return (mapped, Some(MapToError::FrameAllocationFailed));
+        };
+        match unsafe { mapper.map_to(page, frame, HEAP_FLAGS, frame_allocator) }
+        {
+            Ok(flush) => flush.flush(),
+            Err(e) => return (mapped, Some(e)),
+        }
+        mapped += page.size() as usize;
     }
-
-    Ok(())
+    (mapped, None)
 }
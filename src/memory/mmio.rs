@@ -0,0 +1,201 @@
+use core::marker::PhantomData;
+
+use alloc::collections::BTreeMap;
+use anyhow::anyhow;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size1GiB,
+        Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    allocator::BootInfoFrameAllocator, largest_page_size, map_contiguous,
+    supports_1gib_pages, with_mapper,
+};
+
+/// Kernel virtual memory handed out by `map_mmio`.
+pub const MMIO_START: u64 = 0x_6666_0000_0000;
+pub const MMIO_SIZE: u64 = 64 * 1024 * 1024 * 1024; // 64 GiB
+/// Uncached, as device registers must not be cached or combined.
+pub const MMIO_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+static MMIO_RANGES: Mutex<VirtualRangeAllocator> =
+    Mutex::new(VirtualRangeAllocator::new(MMIO_START, MMIO_SIZE));
+
+/// First-fit allocator for ranges of kernel virtual addresses. Only the
+/// ranges in use are stored, the gaps between them are free.
+pub struct VirtualRangeAllocator {
+    start: u64,
+    end: u64,
+    /// Start and size of every range in use.
+    used: BTreeMap<u64, u64>,
+}
+
+impl VirtualRangeAllocator {
+    pub const fn new(start: u64, size: u64) -> Self {
+        Self {
+            start,
+            end: start + size,
+            used: BTreeMap::new(),
+        }
+    }
+
+    /// Reserves `size` bytes aligned to `alignment`, a power of two.
+    pub fn allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let mut candidate = self.start.next_multiple_of(alignment);
+        for (&start, &used) in &self.used {
+            if candidate + size <= start {
+                break;
+            }
+            candidate =
+                candidate.max((start + used).next_multiple_of(alignment));
+        }
+        if size == 0 || candidate + size > self.end {
+            return None;
+        }
+        self.used.insert(candidate, size);
+        Some(candidate)
+    }
+
+    /// Size of the range starting at `start`, if it is in use.
+    pub fn size(&self, start: u64) -> Option<u64> {
+        self.used.get(&start).copied()
+    }
+
+    /// Frees the range starting at `start` and returns its size.
+    pub fn deallocate(&mut self, start: u64) -> Option<u64> {
+        self.used.remove(&start)
+    }
+}
+
+/// Maps `len` bytes of device memory at `phys` uncached into kernel space
+/// and returns the address of `phys` there. Ranges aligned to 2 MiB or
+/// 1 GiB are mapped with huge pages.
+pub fn map_mmio(phys: PhysAddr, len: u64) -> anyhow::Result<VirtAddr> {
+    let phys_start = phys.align_down(Size4KiB::SIZE);
+    let offset = phys - phys_start;
+    let size = (offset + len).next_multiple_of(Size4KiB::SIZE);
+    let alignment = largest_page_size(
+        phys_start.as_u64(),
+        phys_start.as_u64(),
+        size,
+        supports_1gib_pages(),
+    );
+    let start =
+        without_interrupts(|| MMIO_RANGES.lock().allocate(size, alignment))
+            .ok_or(anyhow!("Out of MMIO address space for {:?}", phys))?;
+
+    let mapped = with_mapper(|mapper| {
+        map_contiguous(
+            VirtAddr::new(start),
+            phys_start,
+            size,
+            MMIO_FLAGS,
+            mapper,
+            &mut BootInfoFrameAllocator::shared(),
+        )
+    });
+    if let Err(e) = mapped {
+        with_mapper(|mapper| unmap_range(mapper, start, size))?;
+        without_interrupts(|| MMIO_RANGES.lock().deallocate(start));
+        return Err(e);
+    }
+    Ok(VirtAddr::new(start + offset))
+}
+
+/// Unmaps a range mapped by `map_mmio`, `addr` being the address it
+/// returned. The physical memory is left alone.
+pub fn unmap_mmio(addr: VirtAddr) -> anyhow::Result<()> {
+    let start = addr.align_down(Size4KiB::SIZE).as_u64();
+    let size = without_interrupts(|| MMIO_RANGES.lock().size(start))
+        .ok_or(anyhow!("{:?} was not mapped with map_mmio", addr))?;
+    with_mapper(|mapper| unmap_range(mapper, start, size))?;
+    without_interrupts(|| MMIO_RANGES.lock().deallocate(start));
+    Ok(())
+}
+
+/// Unmaps whatever pages of any size are mapped in `[start, start + size)`.
+fn unmap_range(
+    mapper: &mut OffsetPageTable,
+    start: u64,
+    size: u64,
+) -> anyhow::Result<()> {
+    let mut addr = start;
+    while addr < start + size {
+        let virt = VirtAddr::new(addr);
+        let unmapped = match mapper.translate(virt) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size1GiB(_),
+                ..
+            } => mapper
+                .unmap(Page::<Size1GiB>::containing_address(virt))
+                .map(|(_, flush)| flush.flush())
+                .map(|()| Size1GiB::SIZE),
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => mapper
+                .unmap(Page::<Size2MiB>::containing_address(virt))
+                .map(|(_, flush)| flush.flush())
+                .map(|()| Size2MiB::SIZE),
+            TranslateResult::Mapped { .. } => mapper
+                .unmap(Page::<Size4KiB>::containing_address(virt))
+                .map(|(_, flush)| flush.flush())
+                .map(|()| Size4KiB::SIZE),
+            _ => Ok(Size4KiB::SIZE),
+        };
+        addr += unmapped
+            .map_err(|e| anyhow!("Failed to unmap {:?}: {:?}", virt, e))?;
+    }
+    Ok(())
+}
+
+/// A memory-mapped device register, accessed with volatile reads and
+/// writes.
+#[derive(Debug)]
+pub struct Mmio<T: Copy> {
+    addr: VirtAddr,
+    _phantom_data: PhantomData<T>,
+}
+
+impl<T: Copy> Mmio<T> {
+    /// # Safety
+    /// `addr` must be a mapped, suitably aligned register of type `T`, e.g.
+    /// inside a range returned by `map_mmio`.
+    pub unsafe fn new(addr: VirtAddr) -> Self {
+        Self {
+            addr,
+            _phantom_data: PhantomData,
+        }
+    }
+    pub fn read(&self) -> T {
+        unsafe { core::ptr::read_volatile(self.addr.as_ptr()) }
+    }
+    pub fn write(&self, value: T) {
+        unsafe { core::ptr::write_volatile(self.addr.as_mut_ptr(), value) };
+    }
+}
+
+#[test_case]
+fn virtual_ranges_fill_gaps() {
+    let mut ranges = VirtualRangeAllocator::new(0x1000_0000, 0x40_0000);
+    let a = ranges.allocate(0x1000, 0x1000).unwrap();
+    let b = ranges.allocate(0x2000, 0x1000).unwrap();
+    let c = ranges.allocate(0x1000, 0x1000).unwrap();
+    assert_eq!((a, b, c), (0x1000_0000, 0x1000_1000, 0x1000_3000));
+
+    assert_eq!(ranges.deallocate(b), Some(0x2000));
+    assert_eq!(ranges.allocate(0x1000, 0x1000), Some(b));
+    assert_eq!(ranges.allocate(0x1000, 0x20_0000), Some(0x1020_0000));
+    assert_eq!(ranges.allocate(0x20_0000, 0x1000), None);
+    assert_eq!(ranges.size(c), Some(0x1000));
+}
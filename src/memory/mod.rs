@@ -1,6 +1,8 @@
 pub mod allocator;
 
 mod heap;
+mod mmio;
 mod pager;
 pub use heap::*;
+pub use mmio::*;
 pub use pager::*;
@@ -1,19 +1,104 @@
+use anyhow::anyhow;
+use core::ops::Range;
+use spin::{Mutex, Once};
 use x86_64::{
-    registers::control::Cr3,
+    instructions::interrupts::without_interrupts,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr3},
+        model_specific::{Efer, EferFlags},
+    },
     structures::paging::{
         page_table::FrameError, FrameAllocator, Mapper, OffsetPageTable, Page,
-        PageTable, PhysFrame, Size4KiB,
+        PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB,
+        Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
-pub unsafe fn init(
-    physical_memory_offset: VirtAddr,
-) -> OffsetPageTable<'static> {
+use super::{
+    allocator::MAX_PHYSICAL_MEMORY, heap_limit, HEAP_MAX_SIZE, HEAP_START,
+};
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+/// The one mapper of the active page table, see [`with_mapper`].
+static MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+
+/// Sets up paging and the kernel mapper.
+///
+/// # Safety
+/// The complete physical memory must be mapped at `physical_memory_offset`.
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
     unsafe {
+        // Without NXE the NO_EXECUTE page table bit is reserved.
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        // Make read-only pages fault on kernel writes too, or copy-on-write
+        // pages could be changed in place.
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
         let level_4_table = active_level_4_table(physical_memory_offset);
-        OffsetPageTable::new(level_4_table, physical_memory_offset)
+        MAPPER.call_once(|| {
+            Mutex::new(OffsetPageTable::new(
+                level_4_table,
+                physical_memory_offset,
+            ))
+        });
+    }
+}
+
+/// Runs `f` with the mapper of the active page table, its lock held and
+/// interrupts disabled. Growing the heap takes the mapper as well, so while
+/// `f` runs allocations only succeed if they fit the heap as it is; keep
+/// them out of `f` where possible.
+///
+/// Panics before `init`.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    let mapper = MAPPER.get().expect("Paging is not set up");
+    without_interrupts(|| f(&mut mapper.lock()))
+}
+
+/// Like [`with_mapper`], but `None` instead of waiting if the mapper is in
+/// use, or before `init`. For code that may run while it is held, like the
+/// heap growing or a fault in a `with_mapper` closure.
+pub fn try_with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>) -> R,
+) -> Option<R> {
+    let mapper = MAPPER.get()?;
+    without_interrupts(|| mapper.try_lock().map(|mut mapper| f(&mut mapper)))
+}
+
+/// Whether the mapper is held, e.g. by the code a page fault interrupted.
+pub fn mapper_locked() -> bool {
+    MAPPER.get().is_some_and(|mapper| mapper.is_locked())
+}
+
+/// Where the physical address `phys` is visible in the physical memory
+/// window. `None` before `init`.
+pub fn phys_to_virt(phys: PhysAddr) -> Option<VirtAddr> {
+    Some(*PHYSICAL_MEMORY_OFFSET.get()? + phys.as_u64())
+}
+
+/// Where the bootloader loads the kernel image.
+pub const KERNEL_START: u64 = 0x20_0000;
+
+/// The virtual address ranges the kernel itself uses: its image, the heap
+/// up to its limit and the physical memory window. User memory must stay
+/// clear of all of them.
+pub fn kernel_ranges() -> [Range<u64>; 3] {
+    extern "C" {
+        /// End of the kernel image, defined by the linker.
+        static _end: u8;
     }
+    let kernel_end = core::ptr::addr_of!(_end) as u64;
+    let heap_end = HEAP_START + heap_limit().max(HEAP_MAX_SIZE);
+    let window = phys_to_virt(PhysAddr::new(0)).map_or(0..0, |start| {
+        let start = start.as_u64();
+        start..start.saturating_add(MAX_PHYSICAL_MEMORY)
+    });
+    [
+        KERNEL_START..kernel_end,
+        HEAP_START as u64..heap_end as u64,
+        window,
+    ]
 }
 
 pub fn create_example_mapping(
@@ -64,7 +149,7 @@ fn translate_addr_inner(
     ];
     let mut frame = level_4_table_frame;
 
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
         let table = unsafe { &*table_ptr };
@@ -73,9 +158,141 @@ fn translate_addr_inner(
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            Err(FrameError::HugeFrame) => {
+                // Level 3 entries map 1 GiB pages, level 2 ones 2 MiB.
+                let page_size = match level {
+                    1 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    _ => return None,
+                };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
+
+/// Whether the CPU can map 1 GiB pages (CPUID `Page1GB`).
+pub fn supports_1gib_pages() -> bool {
+    let extended = core::arch::x86_64::__cpuid(0x8000_0001);
+    extended.edx & (1 << 26) != 0
+}
+
+/// The largest page size that can map `virt` to `phys` without going past
+/// `remaining` bytes.
+pub(super) fn largest_page_size(
+    virt: u64,
+    phys: u64,
+    remaining: u64,
+    allow_1gib: bool,
+) -> u64 {
+    let fits = |size: u64| {
+        virt.is_multiple_of(size)
+            && phys.is_multiple_of(size)
+            && remaining >= size
+    };
+    if allow_1gib && fits(Size1GiB::SIZE) {
+        Size1GiB::SIZE
+    } else if fits(Size2MiB::SIZE) {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+/// Maps the physically contiguous range `[phys, phys + len)` at `virt`,
+/// using 1 GiB and 2 MiB pages wherever both addresses are aligned to
+/// them. Used for framebuffers and other device memory by `map_mmio` and
+/// for the physical memory window by [`map_physical_memory`]. Not for the
+/// heap, whose 2 MiB pages can't take an error message while it grows.
+pub fn map_contiguous(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: u64,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> anyhow::Result<()> {
+    if !virt.is_aligned(Size4KiB::SIZE) || !phys.is_aligned(Size4KiB::SIZE) {
+        return Err(anyhow!("{:?} -> {:?} is not page aligned", virt, phys));
+    }
+    let allow_1gib = supports_1gib_pages();
+    let mut offset = 0;
+    while offset < len {
+        let (virt, phys) = (virt + offset, phys + offset);
+        let size = largest_page_size(
+            virt.as_u64(),
+            phys.as_u64(),
+            len - offset,
+            allow_1gib,
+        );
+        unsafe {
+            match size {
+                Size1GiB::SIZE => mapper
+                    .map_to(
+                        Page::<Size1GiB>::containing_address(virt),
+                        PhysFrame::containing_address(phys),
+                        flags,
+                        frame_allocator,
+                    )
+                    .map_err(|e| anyhow!("Failed to map {:?}: {:?}", virt, e))?
+                    .flush(),
+                Size2MiB::SIZE => mapper
+                    .map_to(
+                        Page::<Size2MiB>::containing_address(virt),
+                        PhysFrame::containing_address(phys),
+                        flags,
+                        frame_allocator,
+                    )
+                    .map_err(|e| anyhow!("Failed to map {:?}: {:?}", virt, e))?
+                    .flush(),
+                _ => mapper
+                    .map_to(
+                        Page::<Size4KiB>::containing_address(virt),
+                        PhysFrame::containing_address(phys),
+                        flags,
+                        frame_allocator,
+                    )
+                    .map_err(|e| anyhow!("Failed to map {:?}: {:?}", virt, e))?
+                    .flush(),
+            }
+        }
+        offset += size;
+    }
+    Ok(())
+}
+
+/// Maps all physical memory below `end` at `offset`, like the physical
+/// memory window of the bootloader. That one uses 2 MiB pages; here 1 GiB
+/// pages are used where the CPU has them and `offset` is aligned to them.
+pub fn map_physical_memory(
+    offset: VirtAddr,
+    end: PhysAddr,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> anyhow::Result<()> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE;
+    let len = end.as_u64().next_multiple_of(Size4KiB::SIZE);
+    map_contiguous(
+        offset,
+        PhysAddr::new(0),
+        len,
+        flags,
+        mapper,
+        frame_allocator,
+    )
+}
+
+#[test_case]
+fn largest_page_size_respects_alignment() {
+    const MIB: u64 = 1024 * 1024;
+    const GIB: u64 = 1024 * MIB;
+    assert_eq!(largest_page_size(0, 0, GIB, true), GIB);
+    assert_eq!(largest_page_size(0, 0, GIB, false), 2 * MIB);
+    assert_eq!(largest_page_size(2 * MIB, 4 * MIB, GIB, true), 2 * MIB);
+    assert_eq!(largest_page_size(2 * MIB, 4 * MIB, MIB, true), 4096);
+    assert_eq!(largest_page_size(2 * MIB, 4096, 4 * MIB, true), 4096);
+}
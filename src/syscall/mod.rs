@@ -1,48 +1,58 @@
-use crate::{print, println};
-use x86_64::structures::idt::InterruptStackFrame;
+use crate::{
+    execute::{
+        elf64::USER_SPACE_END,
+        paging::mmap_file,
+        process::CURRENT_PROCESS,
+        tls::{set_fs_base, ARCH_GET_FS, ARCH_SET_FS},
+    },
+    file_system::kernel_fs,
+    gdt::GDT,
+    hlt_loop, print, println,
+};
+use anyhow::anyhow;
+use x86_64::{
+    registers::model_specific::FsBase, structures::idt::InterruptStackFrame,
+    VirtAddr,
+};
 
 pub extern "x86-interrupt" fn syscall_handler(
     stack_frame: InterruptStackFrame,
 ) {
-    let syscall_number: i32;
-    let arga: u32;
-    let argb: u32;
-    let argc: u32;
-    let argd: u32;
+    let eax: u32;
+    let ebx: u32;
+    let ecx: u32;
+    let edx: u32;
+    let esi: u32;
+    let edi: u32;
+    let ebp: u32;
     unsafe {
-        //core::arch::asm!("mov {0:e}, ebx", out(reg) args[0]);
+        // ebx and ebp are reserved by LLVM and have to be moved out
         core::arch::asm!(
             "mov {0:e}, ebx",
-            "mov {1:e}, ecx",
-            "mov {2:e}, esi",
-            "mov {3:e}, edi",
-            "mov {4:e}, ebp",
-            out(reg) syscall_number,
-            out(reg) arga,
-            out(reg) argb,
-            out(reg) argc,
-            out(reg) argd,
-
+            "mov {1:e}, ebp",
+            out(reg) ebx,
+            out(reg) ebp,
+            out("eax") eax,
+            out("ecx") ecx,
+            out("edx") edx,
+            out("esi") esi,
+            out("edi") edi,
         );
     }
-    println!("{} {} {} {} {}", syscall_number, arga, argb, argc, argd,);
-    let syscall_type = match syscall_number {
-        1 => SysCallType::Write,
-        _ => {
+    let syscall = if stack_frame.code_segment == GDT.1.user_code32_selector {
+        SysCall::compat(eax, ebx, ecx, edx)
+    } else {
+        SysCall::native(ebx, ecx, esi, edi, ebp)
+    };
+    match syscall {
+        Some(syscall) => syscall.execute(),
+        None => {
             panic!(
-                "Undefined SysCall: {}\nStack: {:#?}",
-                syscall_number, stack_frame
+                "Undefined SysCall: ebx={} eax={}\nStack: {:#?}",
+                ebx, eax, stack_frame
             );
         }
-    };
-    let syscall = SysCall {
-        syscall_type,
-        arga,
-        argb,
-        argc,
-        argd,
-    };
-    syscall.execute();
+    }
 }
 
 pub struct SysCall {
@@ -54,6 +64,53 @@ pub struct SysCall {
 }
 
 impl SysCall {
+    /// PollOS ABI, see `SYSCALLS.md`.
+    fn native(
+        syscall_number: u32,
+        arga: u32,
+        argb: u32,
+        argc: u32,
+        argd: u32,
+    ) -> Option<Self> {
+        println!("{} {} {} {} {}", syscall_number, arga, argb, argc, argd);
+        let syscall_type = match syscall_number {
+            1 => SysCallType::Write,
+            2 => SysCallType::Exit,
+            3 => SysCallType::ArchPrctl,
+            4 => SysCallType::Mmap,
+            _ => return None,
+        };
+        Some(SysCall {
+            syscall_type,
+            arga,
+            argb,
+            argc,
+            argd,
+        })
+    }
+
+    /// i386 Linux ABI used by 32-bit programs in compatibility mode. The
+    /// arguments are rearranged to match the native ones.
+    fn compat(
+        syscall_number: u32,
+        ebx: u32,
+        ecx: u32,
+        edx: u32,
+    ) -> Option<Self> {
+        let (syscall_type, arga, argb) = match syscall_number {
+            1 => (SysCallType::Exit, ebx, 0),
+            4 => (SysCallType::Write, ecx, edx),
+            _ => return None,
+        };
+        Some(SysCall {
+            syscall_type,
+            arga,
+            argb,
+            argc: 0,
+            argd: 0,
+        })
+    }
+
     fn execute(&self) {
         match &self.syscall_type {
             SysCallType::Write => {
@@ -67,12 +124,99 @@ impl SysCall {
                     print!("{}", byte as char);
                 }
             }
+            SysCallType::Exit => {
+                println!("Process exited with code {}", self.arga as i32);
+                x86_64::instructions::interrupts::enable();
+                hlt_loop();
+            }
+            SysCallType::ArchPrctl => {
+                let addr = self.argb as u64 | (self.argc as u64) << 32;
+                if let Err(e) = arch_prctl(self.arga, addr) {
+                    println!("arch_prctl failed: {}", e);
+                }
+            }
+            SysCallType::Mmap => {
+                let writable = self.argc & MAP_PRIVATE_WRITABLE != 0;
+                if let Err(e) =
+                    mmap(self.arga, self.argb, writable, self.argd as u64)
+                {
+                    println!("mmap failed: {}", e);
+                }
+            }
         }
     }
 }
 
+/// Sets or reads the FS base of the calling thread.
+fn arch_prctl(code: u32, addr: u64) -> anyhow::Result<()> {
+    if addr >= USER_SPACE_END {
+        return Err(anyhow!("{:#x} is not a user address", addr));
+    }
+    match code {
+        ARCH_SET_FS => {
+            set_fs_base(VirtAddr::new(addr));
+            if let Some(process) = CURRENT_PROCESS.lock().as_mut() {
+                process.fs_base = addr;
+            }
+        }
+        ARCH_GET_FS => {
+            let writable = CURRENT_PROCESS
+                .lock()
+                .as_ref()
+                .is_some_and(|process| process.is_user_range(addr, 8, true));
+            if !writable {
+                return Err(anyhow!("{:#x} is not writable user memory", addr));
+            }
+            let fs_base = FsBase::read().as_u64();
+            // Writing may fault the page in, which needs the process.
+            unsafe { (addr as *mut u64).write_unaligned(fs_base) };
+        }
+        _ => return Err(anyhow!("Unknown arch_prctl code {:#x}", code)),
+    }
+    Ok(())
+}
+
+/// Maps the whole file at the path in `[path, path + len)` and writes the
+/// address of the mapping to `*result`.
+fn mmap(
+    path: u32,
+    len: u32,
+    writable: bool,
+    result: u64,
+) -> anyhow::Result<()> {
+    // The address is written as 8 bytes, all of them have to be writable.
+    let valid = CURRENT_PROCESS.lock().as_ref().is_some_and(|process| {
+        process.is_user_range(path as u64, len as u64, false)
+            && process.is_user_range(result, 8, true)
+    });
+    if !valid {
+        return Err(anyhow!("Arguments are not in user memory"));
+    }
+    let path =
+        unsafe { core::slice::from_raw_parts(path as *const u8, len as usize) };
+    let path = core::str::from_utf8(path)?;
+    let fs = kernel_fs().ok_or(anyhow!("No file system"))?;
+    let file = fs.open(path)?;
+    let addr = {
+        let mut process = CURRENT_PROCESS.lock();
+        let process =
+            process.as_mut().ok_or(anyhow!("No process is running"))?;
+        let size = (file.size as u64).max(1);
+        mmap_file(process, fs, &file, 0, size, writable)?
+    };
+    // Writing may fault the page in, which needs the process.
+    unsafe { (result as *mut u64).write_unaligned(addr) };
+    Ok(())
+}
+
+/// `mmap` flag for a private mapping that may be written to.
+const MAP_PRIVATE_WRITABLE: u32 = 1;
+
 pub enum SysCallType {
     Write,
+    Exit,
+    ArchPrctl,
+    Mmap,
 }
 
 impl SysCallType {}
@@ -30,3 +30,17 @@ impl<K: core::hash::Hash + Clone + Eq, V: Clone + Into<K>>
         self.keys.clone()
     }
 }
+
+/// Xorshift pseudo-random numbers for tests, so the order is random but
+/// the same on every run.
+#[derive(Debug, Clone)]
+pub struct XorShift(pub u64);
+
+impl XorShift {
+    pub fn next_usize(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+}
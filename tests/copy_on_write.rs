@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(pollos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use pollos::{
+    execute::paging::{share_page, COPY_ON_WRITE},
+    memory::{self, allocator::BootInfoFrameAllocator},
+};
+use x86_64::{
+    structures::paging::{
+        mapper::TranslateResult, FrameAllocator, Mapper, Page, PageTableFlags,
+        Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+const SOURCE: u64 = 0x4444_0000_0000;
+const COPY: u64 = 0x4444_0001_0000;
+const SECOND_COPY: u64 = 0x4444_0002_0000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    pollos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
+    memory::with_mapper(|mapper| {
+        memory::init_heap(mapper, &mut frame_allocator)
+    })
+    .expect("heap initialization failed");
+
+    let frame = frame_allocator.allocate_frame().unwrap();
+    let page = Page::containing_address(VirtAddr::new(SOURCE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::with_mapper(|mapper| unsafe {
+        mapper
+            .map_to(page, frame, flags, &mut frame_allocator)
+            .unwrap()
+            .flush();
+    });
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    pollos::test_panic_handler(info)
+}
+
+fn flags(addr: u64) -> PageTableFlags {
+    match memory::with_mapper(|mapper| mapper.translate(VirtAddr::new(addr))) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:#x} is not mapped", addr),
+    }
+}
+
+fn translate(addr: u64) -> Option<PhysAddr> {
+    memory::with_mapper(|mapper| mapper.translate_addr(VirtAddr::new(addr)))
+}
+
+fn write(addr: u64, value: u64) {
+    unsafe { core::ptr::write_volatile(addr as *mut u64, value) };
+}
+
+fn read(addr: u64) -> u64 {
+    unsafe { core::ptr::read_volatile(addr as *const u64) }
+}
+
+#[test_case]
+fn write_to_shared_page_copies_it() {
+    write(SOURCE, 1);
+    share_page(VirtAddr::new(SOURCE), VirtAddr::new(COPY)).unwrap();
+    assert!(flags(SOURCE).contains(COPY_ON_WRITE));
+    assert!(!flags(COPY).contains(PageTableFlags::WRITABLE));
+    assert_eq!(read(COPY), 1);
+
+    write(COPY, 2);
+    assert_eq!(read(COPY), 2);
+    assert_eq!(read(SOURCE), 1);
+    assert!(flags(COPY).contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn last_reference_is_written_in_place() {
+    let before = translate(SOURCE).unwrap();
+    share_page(VirtAddr::new(SOURCE), VirtAddr::new(SECOND_COPY)).unwrap();
+    write(SECOND_COPY, 3);
+    // The source is the only page left on its frame.
+    write(SOURCE, 4);
+    let after = translate(SOURCE).unwrap();
+    assert_eq!(before, after);
+    assert_eq!(read(SOURCE), 4);
+    assert_eq!(read(SECOND_COPY), 3);
+}
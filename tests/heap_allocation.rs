@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(pollos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use pollos::memory::{
+    self,
+    allocator::{BootInfoFrameAllocator, ALLOCATOR},
+    HEAP_MAX_SIZE, HEAP_SIZE,
+};
+use pollos::utils::XorShift;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    pollos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
+    memory::with_mapper(|mapper| {
+        memory::init_heap(mapper, &mut frame_allocator)
+    })
+    .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    pollos::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn stats_track_live_allocations() {
+    let before = ALLOCATOR.stats();
+    let value = Box::new([0u8; 100]);
+    let during = ALLOCATOR.stats();
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 100);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+    drop(value);
+    let after = ALLOCATOR.stats();
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert!(after.largest_free_block > 0);
+}
+
+#[test_case]
+fn many_boxes() {
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn random_order_frees_leave_heap_usable() {
+    // Without growth the large allocation below has to reuse the freed space.
+    memory::set_heap_limit(memory::heap_size());
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..8 {
+        let mut blocks: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..8 {
+            let size = 2048 + rng.next_usize() % 4096;
+            blocks.push(alloc::vec![0xAA; size]);
+        }
+        while !blocks.is_empty() {
+            let block = blocks.swap_remove(rng.next_usize() % blocks.len());
+            assert!(block.iter().all(|&b| b == 0xAA));
+        }
+    }
+    // Only possible if the freed blocks merged back together.
+    let large = alloc::vec![0u8; HEAP_SIZE / 2];
+    assert_eq!(large.len(), HEAP_SIZE / 2);
+    drop(large);
+    memory::set_heap_limit(HEAP_MAX_SIZE);
+}
+
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let large = alloc::vec![1u8; 2 * HEAP_SIZE];
+    assert!(large.iter().all(|&b| b == 1));
+    assert!(memory::heap_size() > HEAP_SIZE);
+}
@@ -0,0 +1,157 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(pollos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use pollos::memory::{self, allocator::BootInfoFrameAllocator};
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        PageTableFlags, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+/// 2 MiB aligned, so `map_contiguous` picks a 2 MiB page.
+const HUGE: u64 = 0x5555_0000_0000;
+const PHYS: u64 = 0x20_0000;
+const SIZE: u64 = 0x20_0000;
+/// A second physical memory window, mapped by `map_physical_memory`.
+const WINDOW: u64 = 0x6000_0000_0000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    pollos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
+    memory::with_mapper(|mapper| {
+        memory::init_heap(mapper, &mut frame_allocator)
+    })
+    .expect("heap initialization failed");
+
+    memory::with_mapper(|mapper| {
+        memory::map_contiguous(
+            VirtAddr::new(HUGE),
+            PhysAddr::new(PHYS),
+            SIZE,
+            PageTableFlags::PRESENT,
+            mapper,
+            &mut frame_allocator,
+        )
+    })
+    .expect("mapping the huge page failed");
+
+    let end = boot_info
+        .memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap();
+    memory::with_mapper(|mapper| {
+        memory::map_physical_memory(
+            VirtAddr::new(WINDOW),
+            PhysAddr::new(end),
+            mapper,
+            &mut frame_allocator,
+        )
+    })
+    .expect("mapping the physical memory window failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    pollos::test_panic_handler(info)
+}
+
+#[test_case]
+fn mapped_with_a_2mib_page() {
+    let translated = memory::with_mapper(|mapper| {
+        mapper.translate(VirtAddr::new(HUGE + 0x1234))
+    });
+    match translated {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(frame),
+            offset,
+            ..
+        } => {
+            assert_eq!(frame.start_address(), PhysAddr::new(PHYS));
+            assert_eq!(offset, 0x1234);
+        }
+        other => panic!("{:#x} is not a 2 MiB page: {:?}", HUGE, other),
+    }
+}
+
+#[test_case]
+fn translate_addr_walks_into_2mib_pages() {
+    let offset = memory::phys_to_virt(PhysAddr::new(0)).unwrap();
+    for within in [0, 0x1234, 0x1f_f000, SIZE - 1] {
+        let phys = unsafe {
+            memory::translate_addr(VirtAddr::new(HUGE + within), offset)
+        };
+        assert_eq!(phys, Some(PhysAddr::new(PHYS + within)));
+    }
+}
+
+#[test_case]
+fn huge_page_shows_the_physical_memory() {
+    let window = memory::phys_to_virt(PhysAddr::new(PHYS)).unwrap();
+    for within in [0, 0x1234, SIZE - 8] {
+        let huge = unsafe { *((HUGE + within) as *const u64) };
+        let direct = unsafe { *(window + within).as_ptr::<u64>() };
+        assert_eq!(huge, direct);
+    }
+}
+
+#[test_case]
+fn physical_memory_window_uses_huge_pages() {
+    let translated = memory::with_mapper(|mapper| {
+        mapper.translate(VirtAddr::new(WINDOW + PHYS + 0x1234))
+    });
+    match translated {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(_) | MappedFrame::Size1GiB(_),
+            ..
+        } => {}
+        other => panic!("the window is not mapped huge: {:?}", other),
+    }
+    let window = memory::phys_to_virt(PhysAddr::new(PHYS)).unwrap();
+    for within in [0, 0x1234, SIZE - 8] {
+        let huge = unsafe { *((WINDOW + PHYS + within) as *const u64) };
+        let direct = unsafe { *(window + within).as_ptr::<u64>() };
+        assert_eq!(huge, direct);
+    }
+}
+
+#[test_case]
+fn large_heap_growth_uses_2mib_pages() {
+    let len = 4 * SIZE as usize;
+    let buffer: Vec<u8> = Vec::with_capacity(len);
+    let start = buffer.as_ptr() as u64;
+    let huge = (start.next_multiple_of(SIZE)..start + len as u64 - SIZE)
+        .step_by(SIZE as usize)
+        .any(|page| {
+            let translated = memory::with_mapper(|mapper| {
+                mapper.translate(VirtAddr::new(page))
+            });
+            matches!(
+                translated,
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size2MiB(_),
+                    ..
+                }
+            )
+        });
+    assert!(huge, "no 2 MiB page in {:#x}..+{:#x}", start, len);
+}
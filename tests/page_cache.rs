@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(pollos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use pollos::{
+    file_system::{
+        page_cache::{cached_page, cached_pages, evict_unused},
+        File, FileSource,
+    },
+    memory::{self, allocator::BootInfoFrameAllocator},
+};
+use spin::Mutex;
+use x86_64::{structures::paging::FrameDeallocator, VirtAddr};
+
+entry_point!(main);
+
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> =
+    Mutex::new(None);
+
+/// Every file reads as `0x5a` bytes.
+struct Pattern;
+
+impl FileSource for Pattern {
+    fn read_bytes(
+        &self,
+        _file: &File,
+        buffer: &mut [u8],
+        _offset: usize,
+    ) -> anyhow::Result<()> {
+        buffer.fill(0x5a);
+        Ok(())
+    }
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    pollos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
+    memory::with_mapper(|mapper| {
+        memory::init_heap(mapper, &mut frame_allocator)
+    })
+    .expect("heap initialization failed");
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    pollos::test_panic_handler(info)
+}
+
+fn file(size: u32) -> File {
+    File {
+        name: "DATA".into(),
+        ext: "BIN".into(),
+        path: "/DATA.BIN".into(),
+        start_sector: 0,
+        start_cluster: 0,
+        size,
+        time_stamp: Default::default(),
+    }
+}
+
+#[test_case]
+fn only_unmapped_pages_are_evicted() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let file = file(0x2000);
+    let first = cached_page(&Pattern, &file, 0).unwrap();
+    let second = cached_page(&Pattern, &file, 1).unwrap();
+    assert_eq!(cached_pages(), 2);
+
+    let virt = memory::phys_to_virt(first.start_address()).unwrap();
+    assert_eq!(unsafe { *virt.as_ptr::<u8>() }, 0x5a);
+
+    // Both are still held by their "mappings".
+    assert_eq!(evict_unused(), 0);
+    unsafe { frame_allocator.deallocate_frame(second) };
+    assert_eq!(evict_unused(), 1);
+    assert_eq!(cached_pages(), 1);
+    assert_eq!(frame_allocator.reference_count(second), 0);
+
+    unsafe { frame_allocator.deallocate_frame(first) };
+    assert_eq!(evict_unused(), 1);
+    assert_eq!(cached_pages(), 0);
+}
+
+#[test_case]
+fn evicted_pages_are_read_again() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    let file = file(0x1000);
+    let frame = cached_page(&Pattern, &file, 0).unwrap();
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(evict_unused(), 1);
+    assert_eq!(cached_pages(), 0);
+
+    let frame = cached_page(&Pattern, &file, 0).unwrap();
+    assert_eq!(cached_pages(), 1);
+    let virt = memory::phys_to_virt(frame.start_address()).unwrap();
+    assert_eq!(unsafe { *virt.as_ptr::<u8>() }, 0x5a);
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(evict_unused(), 1);
+}
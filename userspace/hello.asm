@@ -0,0 +1,17 @@
+; Headerless binary for the flat loader: nasm -f bin hello.asm -o hello.bin
+bits 64
+org 0x400000
+
+_start:
+    mov ebx, 1
+    mov ecx, HELLO_WORLD
+    mov esi, HELLO_WORLD_LEN
+    int 0x80
+
+    mov ebx, 2
+    xor ecx, ecx
+    int 0x80
+    jmp $
+
+HELLO_WORLD db "Hello, World!", 0xa
+HELLO_WORLD_LEN equ $ - HELLO_WORLD